@@ -0,0 +1,188 @@
+use block_dev::BlockDevice;
+use bmap::BlockMap;
+use checksum::Algorithm;
+use copy::{self, Chunk, ChunkReader, Skip};
+use failure::Error;
+use image::Image;
+use progress::{MultiProgress, Progress};
+use std::fs::File;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the progress lines are redrawn while waiting for the devices to finish.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the image is written, which is the same for every device.
+#[derive(Clone, Copy)]
+struct Settings<'a> {
+    /// What can be left out of the image, if anything.
+    skip: Option<&'a Skip>,
+    /// Whether the devices were discarded before writing.
+    discarded: bool,
+}
+
+/// What happened when writing one image to several devices.
+pub struct Written {
+    /// The number of bytes in the image.
+    pub len: u64,
+    /// The checksum of the image file, if one was asked for.
+    pub digest: Option<Vec<u8>>,
+    /// How writing to each device went, in the order they were given. Each has a map of what was
+    /// written when blocks were left out, as for `copy::copy_sparse`.
+    pub results: Vec<Result<Option<BlockMap>, Error>>,
+}
+
+/// Writes `image` to each of `targets` at the same time, reading and decompressing it only once,
+/// and then reads it again once to verify them all if `verify` is set. Each device has its own
+/// progress line and a device that fails does not stop the others. `discarded` says whether the
+/// devices were discarded before writing.
+///
+/// The image is not verified when its checksum is wrong, as checked with `checksum`, as the
+/// devices have to be wiped or written again anyway.
+pub fn write_all(
+    image: &Image,
+    targets: Vec<(&BlockDevice, File)>,
+    image_len: Option<u64>,
+    skip: Option<&Skip>,
+    checksum: Option<(Algorithm, &[u8])>,
+    discarded: bool,
+    verify: bool,
+) -> Result<Written, Error> {
+    let mut progress = MultiProgress::new();
+    let width = targets
+        .iter()
+        .map(|(blkdev, _)| blkdev.dev_file().display().to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    thread::scope(|scope| {
+        let mut writers = Vec::new();
+        let mut verifiers = Vec::new();
+        let mut workers = Vec::new();
+        for (blkdev, file) in targets {
+            let label = format!("{:width$} ", blkdev.dev_file().display(), width = width);
+            let (writer, image) = copy::chunk_channel();
+            writers.push(writer);
+            let verify_image = if verify {
+                let (verifier, verify_image) = copy::chunk_channel();
+                verifiers.push(verifier);
+                Some(verify_image)
+            } else {
+                None
+            };
+            let line = progress.add(format!("{}Writing  ", label), image_len);
+            let settings = Settings { skip, discarded };
+            workers.push(scope.spawn(move || {
+                write_device(blkdev, file, image, verify_image, settings, line, &label)
+            }));
+        }
+
+        let read = read_image(image, writers, verifiers, checksum, &mut progress);
+        // The devices carry on flushing and verifying after the image has been read.
+        while workers.iter().any(|worker| !worker.is_finished()) {
+            progress.draw();
+            thread::sleep(REDRAW_INTERVAL);
+        }
+        progress.finish();
+
+        let results = workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|_| Err(format_err!("writing to the device panicked")))
+            })
+            .collect();
+        let (len, digest) = read?;
+        Ok(Written {
+            len,
+            digest,
+            results,
+        })
+    })
+}
+
+/// Reads the image once for writing and, unless its checksum is wrong, once more for verifying,
+/// sending it to every device. Returns the length and checksum of the image.
+fn read_image(
+    image: &Image,
+    writers: Vec<SyncSender<Arc<Chunk>>>,
+    verifiers: Vec<SyncSender<Arc<Chunk>>>,
+    checksum: Option<(Algorithm, &[u8])>,
+    progress: &mut MultiProgress,
+) -> Result<(u64, Option<Vec<u8>>), Error> {
+    let mut reader = match checksum {
+        Some((algorithm, _)) => image.checksum_reader(algorithm)?,
+        None => image.reader()?,
+    };
+    let len = copy::fan_out(&mut reader, writers, || progress.draw())?;
+    let digest = reader.finish_checksum()?;
+
+    let matches = match (checksum, digest.as_ref()) {
+        (Some((_, expected)), Some(digest)) => expected == &digest[..],
+        _ => true,
+    };
+    if matches && !verifiers.is_empty() {
+        let mut reader = image.reader()?;
+        copy::fan_out(&mut reader, verifiers, || progress.draw())?;
+    }
+    Ok((len, digest))
+}
+
+/// Writes what is read from `image` to one device, flushes it and verifies it against
+/// `verify_image` if given, updating `progress` throughout.
+fn write_device(
+    blkdev: &BlockDevice,
+    mut file: File,
+    mut image: ChunkReader,
+    verify_image: Option<ChunkReader>,
+    settings: Settings,
+    mut progress: Progress,
+    label: &str,
+) -> Result<Option<BlockMap>, Error> {
+    let sectors_before = blkdev.sectors_written().ok();
+    let (copied, written) = match settings.skip {
+        Some(skip) => {
+            let written = copy::copy_sparse(&mut image, &file, skip, &mut progress)?;
+            (written.len, Some(written))
+        }
+        None => (copy::copy(&mut image, &mut file, &mut progress)?, None),
+    };
+
+    let needed = match (settings.skip, written.as_ref()) {
+        (Some(skip), Some(written)) => {
+            let needed = skip.needed(written.len);
+            if !(settings.discarded && blkdev.discard_zeroes_data()) {
+                progress.relabel(
+                    format!("{}Zeroing  ", label),
+                    needed.mapped_len().saturating_sub(written.mapped_len()),
+                );
+                copy::zero_gaps(&file, &needed, written, &mut progress)?;
+            }
+            Some(needed)
+        }
+        _ => None,
+    };
+
+    progress.relabel(
+        format!("{}Flushing ", label),
+        written.as_ref().map_or(copied, BlockMap::mapped_len),
+    );
+    copy::sync(file, blkdev, sectors_before, &mut progress)?;
+
+    if let Some(mut verify_image) = verify_image {
+        let mut device_file = copy::open_direct(blkdev.dev_file())?;
+        progress.relabel(format!("{}Verifying", label), copied);
+        if let Some(mismatch) =
+            copy::verify(&mut verify_image, &mut device_file, needed.as_ref(), &mut progress)?
+        {
+            bail!(
+                "Verification failed: {}. The device may be faulty or counterfeit.",
+                mismatch
+            );
+        }
+    }
+    Ok(written)
+}
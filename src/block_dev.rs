@@ -1,35 +1,61 @@
+use failure::Error;
 use itertools::Itertools;
-use std::ffi::OsStr;
+use libc;
+use probe::{self, Filesystem};
+use std::ffi::{CString, OsStr};
 use std::fmt;
-use std::fs::{self, read_to_string};
+use std::fs::{self, read_to_string, File, OpenOptions};
 use std::io;
 use std::num::ParseIntError;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-#[cfg(not(test))]
-static PROC_MOUNTS: &'static str = "/proc/mounts";
-#[cfg(test)]
-static PROC_MOUNTS: &'static str = "src/tests/mounts";
-
 #[derive(Debug, PartialEq)]
 pub struct BlockDevice {
     /// The sysfs block device path
     sys_path: PathBuf,
     /// A human readable label or name for the device.
     label: String,
+    /// The vendor reported by the device, if any.
+    vendor: Option<String>,
+    /// The model reported by the device, if any.
+    model: Option<String>,
     /// The size in bytes of the device.
     size: Size,
     /// The detected general type of the device.
     device_type: DeviceType,
+    /// How the device is attached to the system, if known.
+    transport: Option<Transport>,
     /// Flags that indicate a risky device. If any are present then the device is one we don't want
     /// to write to.
     flags: Vec<Flags>,
+    /// The scanner the device was found with, used to look up what is using the device.
+    scanner: Scanner,
+}
+
+/// Finds block devices and the information about them. By default this reads from the running
+/// system, but it can be pointed at a snapshot instead, such as the fixtures in `src/tests/sysfs`
+/// or a copy of a misdetected device made with `src/tests/clone_dev.sh`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scanner {
+    /// The directory with an entry for each block device, normally `/sys/block`.
+    sysfs_root: PathBuf,
+    /// The table of mounted filesystems in the mountinfo format, normally
+    /// `/proc/self/mountinfo`.
+    mounts_file: PathBuf,
+    /// The table of active swap space, normally `/proc/swaps`.
+    swaps_file: PathBuf,
+    /// Whether to open the device files to check if they are in use and what is on them. This
+    /// only makes sense when scanning the running system.
+    probe_devices: bool,
 }
 
 /// The general type of a block device. FlashDrives and SDMMC are considered safe to write to
 /// while other devices are not.
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DeviceType {
     /// USB flash drives, typically devices that you will want to write OS and Live USB images to.
     /// Note that this can include some SDMMC adaptors that present themselves as SCSI devices.
@@ -44,8 +70,8 @@ pub enum DeviceType {
     /// HDDs. There is a case for using being able to force a write to these devices but should be
     /// hidden by default.
     ///
-    /// *Note* I have not yet found a way to tell these appart from InternalDrives so this is
-    /// currently unused and may be dropped in the future.
+    /// These are told apart from FlashDrives by being attached over USB while not being removable
+    /// and either rotational or large.
     ExternalDrive,
     /// A cd-rom drive. These are block devices but should never be considered for possible
     /// location to write to.
@@ -53,6 +79,33 @@ pub enum DeviceType {
     /// Loopback devices. These are virtual devices and should never be considered for possible
     /// locations to write to.
     LoopBack,
+    /// The drives of a virtual machine, such as virtio and Xen disks. These are treated like
+    /// internal drives as they normally hold the guest's own OS.
+    VirtualDrive,
+    /// Software RAID arrays. These are built from other block devices and should never be
+    /// considered for possible locations to write to.
+    Raid,
+    /// Device mapper devices such as LVM volumes and encrypted disks. These are built from other
+    /// block devices and should never be considered for possible locations to write to.
+    DeviceMapper,
+    /// Compressed RAM and RAM disks. These only exist in memory and should never be considered for
+    /// possible locations to write to.
+    RamDisk,
+    /// Network block devices. These are backed by storage on another machine and should never be
+    /// considered for possible locations to write to.
+    NetworkBlock,
+}
+
+/// The bus a block device is attached through, found by walking up the parents of the device in
+/// sysfs.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Transport {
+    Usb,
+    Ata,
+    Nvme,
+    Virtio,
+    Xen,
+    Mmc,
 }
 
 /// Additional reasons why a device might not be considered safe to write an OS image to, such as
@@ -71,50 +124,225 @@ pub enum Flags {
     /// should not be listed by default. This includes devices that are >36GB in size (ie ~32GB
     /// devices are ok with some buffer for variation in device size).
     Large,
+    /// A device that is smaller than the image that is to be written to it.
+    TooSmall,
+    /// Indicates the device or one of its partitions is in use by something other than a mounted
+    /// filesystem, such as swap, LVM, dm-crypt or an md array.
+    InUse,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Size(pub u64);
 
+/// Devices larger than this are flagged as `Large`. This is 35GiB so 64GB flash drives are
+/// excluded but 32GiB ones are included. This is currently fairly arbitary.
+pub const LARGE_DEVICE_SIZE: Size = Size(35 * 1024 * 1024 * 1024 / 512);
+
+/// Something other than a mounted filesystem that is using a block device or one of its
+/// partitions.
+#[derive(Debug, PartialEq)]
+pub enum Usage {
+    /// Another block device is built on top of it, such as an LVM volume, a dm-crypt mapping or an
+    /// md array.
+    Holder { device: String, holder: String },
+    /// It is active swap space.
+    Swap { device: PathBuf },
+}
+
+/// A partition on a block device.
+#[derive(Debug, PartialEq)]
+pub struct Partition {
+    /// The kernel's name for the partition, such as `sdb1`.
+    pub name: String,
+    /// The number of the partition in the partition table.
+    pub number: u32,
+    /// Where the partition starts on the device.
+    pub start: Size,
+    pub size: Size,
+    /// What is on the partition, if the device could be read and it was recognised.
+    pub filesystem: Option<Filesystem>,
+}
+
+/// A filesystem mounted from a block device or one of its partitions.
+#[derive(Debug, PartialEq)]
+pub struct Mount {
+    /// The device file the filesystem is mounted from.
+    pub source: PathBuf,
+    /// Where the filesystem is mounted.
+    pub target: PathBuf,
+}
+
+/// Why a block device, or the list of them, could not be read.
+#[derive(Debug, Fail)]
+pub enum ScanError {
+    /// The directory of block devices could not be listed.
+    #[fail(display = "could not list block devices in {}: {}", path, err)]
+    List {
+        path: String,
+        #[cause]
+        err: io::Error,
+    },
+    /// An attribute of a device could not be read, such as when the device is removed while it is
+    /// being scanned.
+    #[fail(display = "could not read '{}' of {}: {}", attribute, path, err)]
+    Read {
+        path: String,
+        attribute: &'static str,
+        #[cause]
+        err: io::Error,
+    },
+    /// An attribute of a device did not contain what was expected.
+    #[fail(display = "could not parse '{}' of {}: {:?}", attribute, path, value)]
+    Parse {
+        path: String,
+        attribute: &'static str,
+        value: String,
+    },
+    /// The name of the device is not valid unicode.
+    #[fail(display = "the name of {} is not valid unicode", path)]
+    InvalidName { path: String },
+    /// The mounted filesystems could not be read.
+    #[fail(display = "could not read mounts from {}: {}", path, err)]
+    Mounts {
+        path: String,
+        #[cause]
+        err: io::Error,
+    },
+    /// The active swap space could not be read.
+    #[fail(display = "could not read swaps from {}: {}", path, err)]
+    Swaps {
+        path: String,
+        #[cause]
+        err: io::Error,
+    },
+}
+
 pub struct BlockDeviceIter {
     inner: fs::ReadDir,
+    scanner: Scanner,
 }
 
-pub fn block_devices() -> io::Result<BlockDeviceIter> {
-    Ok(BlockDeviceIter {
-        inner: fs::read_dir("/sys/block")?,
-    })
+impl Scanner {
+    pub fn new(sysfs_root: impl Into<PathBuf>, mounts_file: impl Into<PathBuf>) -> Scanner {
+        Scanner {
+            sysfs_root: sysfs_root.into(),
+            mounts_file: mounts_file.into(),
+            swaps_file: PathBuf::from("/proc/swaps"),
+            probe_devices: true,
+        }
+    }
+
+    /// Returns true if the scanner reads from the running system rather than a snapshot, so that
+    /// the device files it names are the devices it describes.
+    pub fn is_live(&self) -> bool {
+        self.sysfs_root == Path::new("/sys/block")
+            && self.mounts_file == Path::new("/proc/self/mountinfo")
+            && self.swaps_file == Path::new("/proc/swaps")
+    }
+
+    /// The table of mounted filesystems the scanner reads.
+    pub fn mounts_file(&self) -> &Path {
+        &self.mounts_file
+    }
+
+    /// Reads active swap space from `swaps_file` instead of `/proc/swaps`.
+    pub fn swaps_file(mut self, swaps_file: impl Into<PathBuf>) -> Scanner {
+        self.swaps_file = swaps_file.into();
+        self
+    }
+
+    /// Sets whether device files are opened to check if they are in use and what is on them. This
+    /// should be turned off when scanning a snapshot as the device files would not match it.
+    pub fn probe_devices(mut self, probe_devices: bool) -> Scanner {
+        self.probe_devices = probe_devices;
+        self
+    }
+
+    pub fn block_devices(&self) -> Result<BlockDeviceIter, ScanError> {
+        Ok(BlockDeviceIter {
+            inner: fs::read_dir(&self.sysfs_root).map_err(|err| ScanError::List {
+                path: self.sysfs_root.display().to_string(),
+                err,
+            })?,
+            scanner: self.clone(),
+        })
+    }
+
+    /// Looks up the block device behind a device file such as `/dev/sdb`. Symlinks to the device
+    /// file, such as those in `/dev/disk/by-id`, are followed and a bare device name like `sdb` is
+    /// looked up in `/dev`. Device files that do not exist are looked up by name so devices in a
+    /// snapshot can be found.
+    pub fn block_device(&self, dev_file: impl AsRef<Path>) -> Result<BlockDevice, Error> {
+        let dev_file = dev_file.as_ref();
+        let dev_file = if dev_file.components().count() == 1 && !dev_file.exists() {
+            PathBuf::from("/dev").join(dev_file)
+        } else {
+            dev_file.to_path_buf()
+        };
+        let dev_file = if dev_file.exists() {
+            fs::canonicalize(dev_file)?
+        } else {
+            dev_file
+        };
+        let sys_path = match dev_file.file_name() {
+            Some(name) => self.sysfs_root.join(name),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not a device file",
+                ).into())
+            }
+        };
+        if !sys_path.exists() {
+            return Err(if dev_file.exists() {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not a whole block device", dev_file.display()),
+                )
+            } else {
+                io::Error::from(io::ErrorKind::NotFound)
+            }.into());
+        }
+        let mut blkdev = BlockDevice::new(sys_path, self)?;
+        run_checks(&mut blkdev)?;
+        Ok(blkdev)
+    }
 }
 
 impl BlockDevice {
-    pub fn new(sys_path: PathBuf) -> Result<BlockDevice, io::Error> {
+    pub fn new(sys_path: PathBuf, scanner: &Scanner) -> Result<BlockDevice, ScanError> {
         let mut label_parts = Vec::with_capacity(2);
 
-        let vendor = if_exists!(read_to_string(sys_path.join("device/vendor")))?;
-        let model = if_exists!(read_to_string(sys_path.join("device/model")))?;
+        let vendor = read_attr(&sys_path, "device/vendor")?
+            .map(|vendor| vendor.trim().to_string())
+            .filter(|vendor| !vendor.is_empty());
+        let model = read_attr(&sys_path, "device/model")?
+            .map(|model| model.trim().to_string())
+            .filter(|model| !model.is_empty());
 
-        if let Some(vendor) = vendor {
-            label_parts.push(vendor.trim().to_string())
+        if let Some(ref vendor) = vendor {
+            label_parts.push(vendor.clone())
         }
 
-        if let Some(model) = model {
-            label_parts.push(model.trim().to_string())
+        if let Some(ref model) = model {
+            label_parts.push(model.clone())
         }
 
-        let size = read_to_string(sys_path.join("size"))
-            .expect("failed to read size")
-            .trim()
-            .parse()
-            .expect("could not parse device size");
+        let size = parse_attr(&sys_path, "size")?;
 
-        let device_type = BlockDevice::workout_type(&sys_path)?;
+        let transport = Transport::detect(&sys_path)?;
+        let device_type = BlockDevice::workout_type(&sys_path, transport, size)?;
 
         Ok(BlockDevice {
             sys_path,
             label: label_parts.join(" "),
+            vendor,
+            model,
             size,
             device_type,
+            transport,
             flags: Vec::new(),
+            scanner: scanner.clone(),
         })
     }
 
@@ -122,16 +350,41 @@ impl BlockDevice {
         self.device_type
     }
 
+    pub fn transport(&self) -> Option<Transport> {
+        self.transport
+    }
+
     pub fn flags(&self) -> &[Flags] {
         &self.flags
     }
 
-    pub fn label<'a>(&'a self) -> &'a str {
+    pub fn label(&self) -> &str {
         &self.label
     }
 
+    pub fn vendor(&self) -> Option<&str> {
+        self.vendor.as_deref()
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    /// Finds the serial number of the device. SD cards and NVMe drives report it themselves while
+    /// USB drives have it on the USB device they are part of, so the nearest `serial` attribute
+    /// up the device's parents is used.
+    pub fn serial(&self) -> Option<String> {
+        let device = fs::canonicalize(self.sys_path.join("device")).ok()?;
+        device
+            .ancestors()
+            .take_while(|parent| parent.file_name() != Some(OsStr::new("devices")))
+            .filter_map(|parent| read_to_string(parent.join("serial")).ok())
+            .map(|serial| serial.trim().to_string())
+            .find(|serial| !serial.is_empty())
+    }
+
     pub fn dev_name(&self) -> &OsStr {
-        &self.sys_path
+        self.sys_path
             .file_name()
             .expect("missing file name on device path")
     }
@@ -148,29 +401,260 @@ impl BlockDevice {
         self.size
     }
 
-    pub fn workout_type(blkdev_path: impl AsRef<Path>) -> Result<DeviceType, io::Error> {
+    /// Lists the filesystems mounted from the device or any of its partitions. Mounts are matched
+    /// by device number so they are found however the device was named when it was mounted. Some
+    /// filesystems, such as btrfs, report an anonymous device number instead so mounts are also
+    /// matched by their source once any symlinks to the device node have been followed.
+    pub fn mounts(&self) -> io::Result<Vec<Mount>> {
+        let numbers = self.dev_numbers()?;
+        Ok(read_to_string(&self.scanner.mounts_file)?
+            .lines()
+            .filter_map(parse_mountinfo_line)
+            .map(|(number, source, target)| {
+                let mount = Mount {
+                    source: PathBuf::from(unescape_mount_path(source)),
+                    target: PathBuf::from(unescape_mount_path(target)),
+                };
+                (number, mount)
+            })
+            .filter(|(number, mount)| {
+                let source = if mount.source.starts_with("/dev") {
+                    fs::canonicalize(&mount.source).unwrap_or_else(|_| mount.source.clone())
+                } else {
+                    PathBuf::new()
+                };
+                let dev_name = source.strip_prefix("/dev").ok().and_then(Path::to_str);
+                numbers
+                    .iter()
+                    .any(|(name, n)| n == number || dev_name == Some(name.as_str()))
+            })
+            .map(|(_, mount)| mount)
+            .collect())
+    }
+
+    /// Lists the partitions on the device in the order they are numbered, along with what is on
+    /// them.
+    pub fn partitions(&self) -> Result<Vec<Partition>, ScanError> {
+        let paths = self.partition_paths().map_err(|err| ScanError::Read {
+            path: self.sys_path.display().to_string(),
+            attribute: "partitions",
+            err,
+        })?;
+        let mut partitions = paths
+            .iter()
+            .map(|path| {
+                let name = path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                Ok(Partition {
+                    number: parse_attr(path, "partition")?,
+                    start: parse_attr(path, "start")?,
+                    size: parse_attr(path, "size")?,
+                    filesystem: self.probe(Path::new("/dev").join(&name)),
+                    name,
+                })
+            })
+            .collect::<Result<Vec<_>, ScanError>>()?;
+        partitions.sort_by_key(|partition| partition.number);
+        Ok(partitions)
+    }
+
+    /// Probes the whole device for a filesystem, such as on a card formatted without a partition
+    /// table or an ISO image written to a flash drive.
+    pub fn filesystem(&self) -> Option<Filesystem> {
+        self.probe(self.dev_file())
+    }
+
+    /// Looks for a filesystem on the device file. Devices that cannot be read, normally due to a
+    /// lack of permission, are treated as having no recognised filesystem.
+    fn probe(&self, dev_file: PathBuf) -> Option<Filesystem> {
+        if !self.scanner.probe_devices {
+            return None;
+        }
+        File::open(dev_file)
+            .and_then(|mut file| probe::probe(&mut file))
+            .unwrap_or(None)
+    }
+
+    /// The sysfs paths of the partitions on the device.
+    fn partition_paths(&self) -> io::Result<Vec<PathBuf>> {
+        let mut partitions = Vec::new();
+        for entry in fs::read_dir(&self.sys_path)? {
+            let path = entry?.path();
+            if path.join("partition").exists() {
+                partitions.push(path);
+            }
+        }
+        partitions.sort();
+        Ok(partitions)
+    }
+
+    /// The names and `major:minor` device numbers of the device and each of its partitions.
+    fn dev_numbers(&self) -> io::Result<Vec<(String, String)>> {
+        let mut numbers = Vec::new();
+        for path in Some(self.sys_path.clone()).into_iter().chain(self.partition_paths()?) {
+            if let Some(number) = if_exists!(read_to_string(path.join("dev")))? {
+                let name = path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                numbers.push((name, number.trim().to_string()));
+            }
+        }
+        Ok(numbers)
+    }
+
+    /// Lists the block devices built on top of the device or any of its partitions, such as LVM
+    /// volumes, dm-crypt mappings and md arrays.
+    pub fn holders(&self) -> io::Result<Vec<Usage>> {
+        let mut devices = vec![self.sys_path.clone()];
+        devices.extend(self.partition_paths()?);
+
+        let mut holders = Vec::new();
+        for device in devices {
+            let entries = match if_exists!(fs::read_dir(device.join("holders")))? {
+                Some(entries) => entries,
+                None => continue,
+            };
+            for entry in entries {
+                let entry = entry?;
+                let mut holder = entry.file_name().to_string_lossy().into_owned();
+                // Device mapper devices have far more useful names than dm-N.
+                if let Some(name) = if_exists!(read_to_string(entry.path().join("dm/name")))? {
+                    holder = format!("{} ({})", holder, name.trim());
+                }
+                holders.push(Usage::Holder {
+                    device: device
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    holder,
+                });
+            }
+        }
+        Ok(holders)
+    }
+
+    /// Lists the device or any of its partitions that are active swap space.
+    pub fn swaps(&self) -> io::Result<Vec<Usage>> {
+        let dev_files = self.dev_numbers()?
+            .into_iter()
+            .map(|(name, _)| Path::new("/dev").join(name))
+            .collect::<Vec<_>>();
+        let swaps = match if_exists!(read_to_string(&self.scanner.swaps_file))? {
+            Some(swaps) => swaps,
+            None => return Ok(Vec::new()),
+        };
+        Ok(swaps
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|dev| PathBuf::from(unescape_mount_path(dev)))
+            .filter(|dev| dev_files.contains(dev))
+            .map(|device| Usage::Swap { device })
+            .collect())
+    }
+
+    /// Everything other than mounted filesystems using the device, see `holders` and `swaps`.
+    pub fn usages(&self) -> io::Result<Vec<Usage>> {
+        let mut usages = self.holders()?;
+        usages.extend(self.swaps()?);
+        Ok(usages)
+    }
+
+    /// Tries to open the device file exclusively, which the kernel refuses while anything else has
+    /// the device or one of its partitions claimed. Returns None if the device could not be probed,
+    /// such as when there is no permission to open it. The open does not block, which would close
+    /// the tray of an optical drive and spin up the disc.
+    fn is_claimed(&self) -> Option<bool> {
+        match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_EXCL | libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(self.dev_file())
+        {
+            Ok(_) => Some(false),
+            Err(ref err) if err.raw_os_error() == Some(libc::EBUSY) => Some(true),
+            Err(_) => None,
+        }
+    }
+
+    /// Flags the device as too small if it cannot hold `image_len` bytes.
+    pub fn check_image_fits(&mut self, image_len: u64) {
+        if self.size.bytes() < image_len && !self.flags.contains(&Flags::TooSmall) {
+            self.flags.push(Flags::TooSmall);
+        }
+    }
+
+    /// Whether the device promises that discarded blocks read back as zeros. Most do not, and
+    /// recent kernels always report that they do not.
+    pub fn discard_zeroes_data(&self) -> bool {
+        read_to_string(self.sys_path.join("queue/discard_zeroes_data"))
+            .map(|value| value.trim() == "1")
+            .unwrap_or(false)
+    }
+
+    /// The total number of sectors the kernel has written to the device since it was attached,
+    /// as reported by the device's `stat` file.
+    pub fn sectors_written(&self) -> io::Result<u64> {
+        read_to_string(self.sys_path.join("stat"))?
+            .split_whitespace()
+            .nth(6)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "stat file too short"))?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn workout_type(
+        blkdev_path: impl AsRef<Path>,
+        transport: Option<Transport>,
+        size: Size,
+    ) -> Result<DeviceType, ScanError> {
+        let blkdev_path = blkdev_path.as_ref();
         let dev_name = blkdev_path
-            .as_ref()
             .file_name()
             .expect("missing file name on device")
             .to_str()
-            .expect("none unicode char in device name");
+            .ok_or_else(|| ScanError::InvalidName {
+                path: blkdev_path.display().to_string(),
+            })?;
 
         if dev_name.starts_with("mmcblk") {
             Ok(DeviceType::SDMMC)
         } else if dev_name.starts_with("sd") {
-            if read_to_string(blkdev_path.as_ref().join("removable"))
-                .map(|val| val.trim() == "0")
-                .expect("error reading the 'removable' file")
-            {
-                Ok(DeviceType::InternalDrive)
-            } else {
-                Ok(DeviceType::FlashDrive)
+            // Without the removable attribute there is no telling so assume the worst.
+            let removable = read_attr(blkdev_path, "removable")?
+                .map(|val| val.trim() != "0")
+                .unwrap_or(false);
+            let rotational = read_attr(blkdev_path, "queue/rotational")?
+                .map(|val| val.trim() != "0")
+                .unwrap_or(false);
+            match transport {
+                // Card readers built into laptops are often attached through an internal bridge,
+                // so removable media counts whatever the transport.
+                _ if removable => Ok(DeviceType::FlashDrive),
+                // Many flash drives claim to be rotational so only trust it for fixed drives.
+                Some(Transport::Usb) if rotational || size.0 > LARGE_DEVICE_SIZE.0 => {
+                    Ok(DeviceType::ExternalDrive)
+                }
+                Some(Transport::Usb) => Ok(DeviceType::FlashDrive),
+                _ => Ok(DeviceType::InternalDrive),
             }
         } else if dev_name.starts_with("sr") {
             Ok(DeviceType::CDROM)
         } else if dev_name.starts_with("loop") {
             Ok(DeviceType::LoopBack)
+        } else if dev_name.starts_with("vd") || dev_name.starts_with("xvd") {
+            Ok(DeviceType::VirtualDrive)
+        } else if dev_name.starts_with("md") {
+            Ok(DeviceType::Raid)
+        } else if dev_name.starts_with("dm-") {
+            Ok(DeviceType::DeviceMapper)
+        } else if dev_name.starts_with("zram") || dev_name.starts_with("ram") {
+            Ok(DeviceType::RamDisk)
+        } else if ["nbd", "rbd", "drbd"]
+            .iter()
+            .any(|prefix| dev_name.starts_with(prefix))
+        {
+            Ok(DeviceType::NetworkBlock)
         } else {
             Ok(DeviceType::InternalDrive)
         }
@@ -187,6 +671,31 @@ impl DeviceType {
             DeviceType::ExternalDrive => false,
             DeviceType::CDROM => false,
             DeviceType::LoopBack => false,
+            DeviceType::VirtualDrive => false,
+            DeviceType::Raid => false,
+            DeviceType::DeviceMapper => false,
+            DeviceType::RamDisk => false,
+            DeviceType::NetworkBlock => false,
+        }
+    }
+
+    /// A short human readable explanation of why a device of this type is not considered safe to
+    /// write to. Returns None for safe types.
+    pub fn unsafe_reason(&self) -> Option<&'static str> {
+        match self {
+            DeviceType::FlashDrive => None,
+            DeviceType::SDMMC => None,
+            DeviceType::InternalDrive => Some("it looks like an internal drive"),
+            DeviceType::ExternalDrive => Some("it looks like an external hard drive"),
+            DeviceType::CDROM => Some("it is a cd-rom drive"),
+            DeviceType::LoopBack => Some("it is a loopback device"),
+            DeviceType::VirtualDrive => Some("it looks like a virtual machine's drive"),
+            DeviceType::Raid => Some("it is a software RAID array"),
+            DeviceType::DeviceMapper => {
+                Some("it is a device mapper device, such as an LVM or encrypted volume")
+            }
+            DeviceType::RamDisk => Some("it is a RAM disk"),
+            DeviceType::NetworkBlock => Some("it is a network block device"),
         }
     }
 
@@ -199,70 +708,225 @@ impl DeviceType {
             DeviceType::ExternalDrive => false,
             DeviceType::CDROM => true,
             DeviceType::LoopBack => true,
+            DeviceType::VirtualDrive => false,
+            DeviceType::Raid => true,
+            DeviceType::DeviceMapper => true,
+            DeviceType::RamDisk => true,
+            DeviceType::NetworkBlock => true,
         }
     }
 }
 
-fn run_checks(blkdev: &mut BlockDevice) -> Result<(), io::Error> {
+impl Transport {
+    /// Finds the transport by resolving the device symlink of the block device at `sys_path` and
+    /// looking for a known bus in its parents, nearest first. Virtual devices have no device
+    /// symlink and so no transport.
+    pub fn detect(sys_path: &Path) -> Result<Option<Transport>, ScanError> {
+        let device = match fs::canonicalize(sys_path.join("device")) {
+            Ok(device) => device,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(ScanError::Read {
+                    path: sys_path.display().to_string(),
+                    attribute: "device",
+                    err,
+                })
+            }
+        };
+        Ok(device
+            .ancestors()
+            .filter_map(|parent| parent.file_name().and_then(|name| name.to_str()))
+            .filter_map(Transport::from_sysfs_name)
+            .next())
+    }
+
+    /// Works out the transport from the name of a device in sysfs, such as `usb2` for a USB root
+    /// hub or `ata1` for an ATA port.
+    fn from_sysfs_name(name: &str) -> Option<Transport> {
+        let numbered = |prefix: &str| {
+            name.len() > prefix.len() && name.starts_with(prefix)
+                && name[prefix.len()..].bytes().all(|b| b.is_ascii_digit())
+        };
+        if numbered("usb") {
+            Some(Transport::Usb)
+        } else if numbered("ata") {
+            Some(Transport::Ata)
+        } else if numbered("nvme") {
+            Some(Transport::Nvme)
+        } else if numbered("virtio") {
+            Some(Transport::Virtio)
+        } else if name.starts_with("vbd-") {
+            Some(Transport::Xen)
+        } else if name == "mmc_host" {
+            Some(Transport::Mmc)
+        } else {
+            None
+        }
+    }
+}
+
+impl Flags {
+    /// A short human readable explanation of why the flag makes a device risky to write to.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Flags::Mounted => "it is currently mounted",
+            Flags::ZeroSize => "it has a size of zero, there may be no card inserted",
+            Flags::ReadOnly => "it is read only",
+            Flags::Large => "it is larger than 35GiB and so is unlikely to be meant for OS images",
+            Flags::TooSmall => "it is too small to hold the image",
+            Flags::InUse => "it is in use by the system",
+        }
+    }
+}
+
+fn run_checks(blkdev: &mut BlockDevice) -> Result<(), ScanError> {
     // Is mounted
-    if read_to_string(PROC_MOUNTS)?
-        .lines()
-        .filter_map(|line| line.split_whitespace().next_tuple())
-        .any(|(dev, _)| {
-            let dev_name = blkdev.dev_file();
-            let dev_name = dev_name.to_str().expect("none unicode char in device path");
-            let part_name = PathBuf::from(dev);
-            let part_name = part_name.to_str().expect("none unicode char in mount file");
-
-            part_name.starts_with(dev_name)
-        }) {
+    let mounts = blkdev.mounts().map_err(|err| ScanError::Mounts {
+        path: blkdev.scanner.mounts_file.display().to_string(),
+        err,
+    })?;
+    if !mounts.is_empty() {
         blkdev.flags.push(Flags::Mounted);
     }
 
+    // Is in use in some other way. A mounted device is always claimed so there is no point in
+    // probing those, and devices that are never written to are not opened at all since opening
+    // an optical drive can spin it up.
+    let holders = blkdev.holders().map_err(|err| ScanError::Read {
+        path: blkdev.sys_path.display().to_string(),
+        attribute: "holders",
+        err,
+    })?;
+    let swaps = blkdev.swaps().map_err(|err| ScanError::Swaps {
+        path: blkdev.scanner.swaps_file.display().to_string(),
+        err,
+    })?;
+    let claimed = blkdev.scanner.probe_devices
+        && mounts.is_empty()
+        && !blkdev.device_type.is_excluded()
+        && blkdev.is_claimed().unwrap_or(false);
+    if !holders.is_empty() || !swaps.is_empty() || claimed {
+        blkdev.flags.push(Flags::InUse);
+    }
+
     // Check device size
     if blkdev.size.0 == 0 {
         blkdev.flags.push(Flags::ZeroSize);
-    } else if blkdev.size.0 > 35 * 1024_u64.pow(3) / 512 {
-        // We consider a device large if it is > 35GiB so 64GB flash drives are excluded but 32Gib
-        // ones are included. This is currently fairly arbitary.
+    } else if blkdev.size.0 > LARGE_DEVICE_SIZE.0 {
         blkdev.flags.push(Flags::Large);
     }
 
     // Check if device is read-only
-    if read_to_string(blkdev.sys_path().join("ro"))
-        .expect("failed to read size")
-        .trim()
-        .parse::<u8>()
-        .expect("could not parse device size") > 0
-    {
+    if parse_attr::<u8>(blkdev.sys_path(), "ro")? > 0 {
         blkdev.flags.push(Flags::ReadOnly);
     }
 
     Ok(())
 }
 
+/// Reads an attribute of the device at `sys_path`, returning None if the device does not have it.
+fn read_attr(sys_path: &Path, attribute: &'static str) -> Result<Option<String>, ScanError> {
+    if_exists!(read_to_string(sys_path.join(attribute))).map_err(|err| ScanError::Read {
+        path: sys_path.display().to_string(),
+        attribute,
+        err,
+    })
+}
+
+/// Reads and parses an attribute that every device is expected to have.
+fn parse_attr<T: FromStr>(sys_path: &Path, attribute: &'static str) -> Result<T, ScanError> {
+    let value = read_to_string(sys_path.join(attribute)).map_err(|err| ScanError::Read {
+        path: sys_path.display().to_string(),
+        attribute,
+        err,
+    })?;
+    value.trim().parse().map_err(|_| ScanError::Parse {
+        path: sys_path.display().to_string(),
+        attribute,
+        value,
+    })
+}
+
+/// Unmounts a filesystem.
+pub fn unmount(mount: &Mount) -> io::Result<()> {
+    let target = CString::new(mount.target.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::umount(target.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Splits a line of `/proc/self/mountinfo` into the `major:minor` number of the mounted device,
+/// the mount source and the mount point. The fields are described in proc(5), the source comes
+/// after the optional fields which are ended by a lone `-`.
+fn parse_mountinfo_line(line: &str) -> Option<(&str, &str, &str)> {
+    let mut fields = line.split_whitespace();
+    let number = fields.nth(2)?;
+    let target = fields.nth(1)?;
+    let source = fields.skip_while(|&field| field != "-").nth(2)?;
+    Some((number, source, target))
+}
+
+/// Paths in /proc/self/mountinfo and /proc/swaps have spaces, tabs, new lines and backslashes
+/// escaped as octal.
+fn unescape_mount_path(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 4).and_then(|oct| u8::from_str_radix(oct, 8).ok()) {
+            Some(c) => {
+                unescaped.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Iterates over the block devices. A device that cannot be read is returned as an error and the
+/// iteration carries on with the next one.
 impl Iterator for BlockDeviceIter {
-    type Item = Result<BlockDevice, io::Error>;
-
-    fn next(&mut self) -> Option<Result<BlockDevice, io::Error>> {
-        loop {
-            return match self.inner.next() {
-                Some(Ok(dir)) => {
-                    let mut blkdev = BlockDevice::new(dir.path());
-                    if let Ok(ref mut blkdev) = blkdev {
-                        if let Err(err) = run_checks(blkdev) {
-                            return Some(Err(err));
-                        }
+    type Item = Result<BlockDevice, ScanError>;
+
+    fn next(&mut self) -> Option<Result<BlockDevice, ScanError>> {
+        match self.inner.next() {
+            Some(Ok(dir)) => {
+                let mut blkdev = BlockDevice::new(dir.path(), &self.scanner);
+                if let Ok(ref mut blkdev) = blkdev {
+                    if let Err(err) = run_checks(blkdev) {
+                        return Some(Err(err));
                     }
-                    Some(blkdev)
                 }
-                Some(Err(err)) => Some(Err(err.into())),
-                None => None,
-            };
+                Some(blkdev)
+            }
+            Some(Err(err)) => Some(Err(ScanError::List {
+                path: self.scanner.sysfs_root.display().to_string(),
+                err,
+            })),
+            None => None,
         }
     }
 }
 
+impl Size {
+    /// Creates a size from a length in bytes, rounding down to whole sectors.
+    pub fn from_bytes(bytes: u64) -> Size {
+        Size(bytes / 512)
+    }
+
+    /// The size in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.0 * 512
+    }
+}
+
 impl FromStr for Size {
     type Err = ParseIntError;
 
@@ -293,13 +957,13 @@ impl fmt::Display for Size {
         let size = self.0 * 512;
         let decimals = f.precision().unwrap_or(1);
         let string = match size {
-            0...1024 => format!("{}", size),
-            1024...1_048_576 => format!("{:.*}KiB", decimals, size as f64 / 1024.0),
-            1_048_576...1_073_741_824 => format!("{:.*}MiB", decimals, size as f64 / 1_048_576.0),
-            1_073_741_824...1_099_511_627_776 => {
+            0..=1023 => format!("{}", size),
+            1024..=1_048_575 => format!("{:.*}KiB", decimals, size as f64 / 1024.0),
+            1_048_576..=1_073_741_823 => format!("{:.*}MiB", decimals, size as f64 / 1_048_576.0),
+            1_073_741_824..=1_099_511_627_775 => {
                 format!("{:.*}GiB", decimals, size as f64 / 1_073_741_824.0)
             }
-            _ => format!("{:.*}TiB", decimals, self.0),
+            _ => format!("{:.*}TiB", decimals, size as f64 / 1_099_511_627_776.0),
         };
         f.pad_integral(true, "", &string)
     }
@@ -310,35 +974,62 @@ impl fmt::Display for DeviceType {
         f.pad_integral(
             true,
             "",
-            &format!(
-                "{}",
-                match self {
-                    DeviceType::FlashDrive => "Flash Drive",
-                    DeviceType::SDMMC => "SD/MMC Card",
-                    DeviceType::InternalDrive => "Internal Drive",
-                    DeviceType::ExternalDrive => "External Drive",
-                    DeviceType::CDROM => "CD-ROM",
-                    DeviceType::LoopBack => "LoopBack",
-                }
-            ),
+            match self {
+                DeviceType::FlashDrive => "Flash Drive",
+                DeviceType::SDMMC => "SD/MMC Card",
+                DeviceType::InternalDrive => "Internal Drive",
+                DeviceType::ExternalDrive => "External Drive",
+                DeviceType::CDROM => "CD-ROM",
+                DeviceType::LoopBack => "LoopBack",
+                DeviceType::VirtualDrive => "Virtual Drive",
+                DeviceType::Raid => "RAID",
+                DeviceType::DeviceMapper => "Device Mapper",
+                DeviceType::RamDisk => "RAM Disk",
+                DeviceType::NetworkBlock => "Network Block",
+            },
+        )
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad_integral(
+            true,
+            "",
+            match self {
+                Transport::Usb => "usb",
+                Transport::Ata => "ata",
+                Transport::Nvme => "nvme",
+                Transport::Virtio => "virtio",
+                Transport::Xen => "xen",
+                Transport::Mmc => "mmc",
+            },
         )
     }
 }
 
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Usage::Holder { device, holder } => write!(f, "{} is used by {}", device, holder),
+            Usage::Swap { device } => write!(f, "{} is active swap", device.display()),
+        }
+    }
+}
+
 impl fmt::Display for Flags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.pad_integral(
             true,
             "",
-            &format!(
-                "{}",
-                match self {
-                    Flags::Mounted => "mounted",
-                    Flags::ZeroSize => "zero-size",
-                    Flags::ReadOnly => "read-only",
-                    Flags::Large => "large",
-                }
-            ),
+            match self {
+                Flags::Mounted => "mounted",
+                Flags::ZeroSize => "zero-size",
+                Flags::ReadOnly => "read-only",
+                Flags::Large => "large",
+                Flags::TooSmall => "too-small",
+                Flags::InUse => "in-use",
+            },
         )
     }
 }
@@ -346,7 +1037,7 @@ impl fmt::Display for Flags {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::read_dir;
+    use std::{env, process};
 
     #[derive(Debug)]
     struct DeviceTestCase {
@@ -358,19 +1049,196 @@ mod tests {
         PathBuf::from(file!()).parent().unwrap().join("tests/sysfs")
     }
 
+    fn scanner() -> Scanner {
+        let tests = PathBuf::from(file!()).parent().unwrap().join("tests");
+        Scanner::new(sysfs(), tests.join("mountinfo"))
+            .swaps_file(tests.join("swaps"))
+            .probe_devices(false)
+    }
+
+    #[test]
+    fn displays_sizes() {
+        assert_eq!(Size::from_bytes(512).to_string(), "512");
+        assert_eq!(Size::from_bytes(1024).to_string(), "1.0KiB");
+        assert_eq!(Size::from_bytes(1_048_576).to_string(), "1.0MiB");
+        assert_eq!(Size(31_116_288).to_string(), "14.8GiB");
+        assert_eq!(Size::from_bytes(2 * 1_099_511_627_776).to_string(), "2.0TiB");
+    }
+
     #[test]
     fn device_checks() {
-        for res in read_dir(sysfs()).unwrap() {
-            let dir = res.unwrap();
-            println!("Running test for {}", dir.path().display());
-            let mut blkdev = BlockDevice::new(dir.path()).unwrap();
-            run_checks(&mut blkdev);
-            let test_case = load_device_test(dir.path());
+        for blkdev in scanner().block_devices().unwrap() {
+            let blkdev = blkdev.unwrap();
+            println!("Running test for {}", blkdev.sys_path().display());
+            let test_case = load_device_test(blkdev.sys_path());
             assert_eq!(test_case.device_type, blkdev.device_type);
             assert_eq!(test_case.flags, blkdev.flags);
         }
     }
 
+    #[test]
+    fn finds_devices_in_snapshot() {
+        let blkdev = scanner().block_device("sda").unwrap();
+        assert_eq!(blkdev.sys_path(), sysfs().join("sda").as_path());
+        assert_eq!(blkdev.flags(), &[Flags::Mounted]);
+        assert_eq!(
+            scanner()
+                .block_device("nonexistent")
+                .unwrap_err()
+                .downcast::<io::Error>()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn reports_unreadable_devices() {
+        let root = env::temp_dir().join(format!("scribe-sysfs-{}", process::id()));
+        fs::create_dir_all(root.join("loop7")).unwrap();
+        fs::write(root.join("loop7/size"), "0\n").unwrap();
+        fs::write(root.join("loop7/ro"), "0\n").unwrap();
+        fs::create_dir_all(root.join("sdz")).unwrap();
+        fs::write(root.join("sdz/size"), "lots\n").unwrap();
+
+        let mut results = Scanner::new(&root, sysfs().join("../mountinfo"))
+            .probe_devices(false)
+            .block_devices()
+            .unwrap()
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&root).unwrap();
+        results.sort_by_key(|res| res.is_ok());
+
+        match results[0] {
+            Err(ScanError::Parse { attribute, .. }) => assert_eq!(attribute, "size"),
+            ref res => panic!("expected a parse error, got {:?}", res),
+        }
+        assert_eq!(
+            results[1].as_ref().unwrap().device_type(),
+            DeviceType::LoopBack
+        );
+    }
+
+    #[test]
+    fn finds_usages() {
+        let blkdev = scanner().block_device("sdj").unwrap();
+        assert_eq!(
+            blkdev.usages().unwrap(),
+            vec![
+                Usage::Holder {
+                    device: "sdj1".to_string(),
+                    holder: "dm-0 (vg0-root)".to_string(),
+                },
+                Usage::Swap {
+                    device: PathBuf::from("/dev/sdj2"),
+                },
+            ]
+        );
+        assert_eq!(scanner().block_device("sdb").unwrap().usages().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn lists_partitions() {
+        let partitions = scanner().block_device("nvme0n1").unwrap().partitions().unwrap();
+        assert_eq!(
+            partitions,
+            vec![
+                Partition {
+                    name: "nvme0n1p1".to_string(),
+                    number: 1,
+                    start: Size(2048),
+                    size: Size(1048576),
+                    filesystem: None,
+                },
+                Partition {
+                    name: "nvme0n1p7".to_string(),
+                    number: 7,
+                    start: Size(935135232),
+                    size: Size(65079951),
+                    filesystem: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_transports() {
+        let transport = |name| Transport::detect(&sysfs().join(name)).unwrap();
+        assert_eq!(transport("sdb"), Some(Transport::Usb));
+        assert_eq!(transport("sdi"), Some(Transport::Usb));
+        assert_eq!(transport("nvme0n1"), Some(Transport::Nvme));
+        assert_eq!(transport("sdk"), Some(Transport::Ata));
+        assert_eq!(transport("loop0"), None);
+        assert_eq!(Transport::from_sysfs_name("ata12"), Some(Transport::Ata));
+        assert_eq!(Transport::from_sysfs_name("virtio1"), Some(Transport::Virtio));
+        assert_eq!(Transport::from_sysfs_name("vbd-51712"), Some(Transport::Xen));
+        assert_eq!(Transport::from_sysfs_name("usb"), None);
+        assert_eq!(Transport::from_sysfs_name("2-1:1.0"), None);
+    }
+
+    #[test]
+    fn reads_device_identity() {
+        let device = |name| scanner().block_device(name).unwrap();
+        let sdb = device("sdb");
+        assert_eq!(sdb.vendor(), Some("Kingston"));
+        assert_eq!(sdb.model(), Some("DataTraveler 2.0"));
+        assert_eq!(sdb.serial().as_deref(), Some("001CC0EC3450BB31A9400052"));
+        assert_eq!(
+            device("nvme0n1").serial().as_deref(),
+            Some("S4EVNF0M123456A")
+        );
+        assert_eq!(device("loop0").serial(), None);
+    }
+
+    #[test]
+    fn matches_mounts_by_device_number() {
+        let mounts = |name| scanner().block_device(name).unwrap().mounts().unwrap();
+        assert_eq!(
+            mounts("nvme0n1"),
+            vec![
+                Mount {
+                    source: PathBuf::from("/dev/nvme0n1p7"),
+                    target: PathBuf::from("/"),
+                },
+                Mount {
+                    source: PathBuf::from("/dev/nvme0n1p1"),
+                    target: PathBuf::from("/boot"),
+                },
+            ]
+        );
+        // Mounted through /dev/mapper rather than /dev/dm-0.
+        assert_eq!(
+            mounts("dm-0"),
+            vec![Mount {
+                source: PathBuf::from("/dev/mapper/vg0-root"),
+                target: PathBuf::from("/home"),
+            }]
+        );
+        // /dev/sdbb1 is mounted which starts with /dev/sdb.
+        assert_eq!(mounts("sdb"), vec![]);
+        // btrfs reports an anonymous device number so has to be matched by its source.
+        assert_eq!(
+            mounts("sda"),
+            vec![
+                Mount {
+                    source: PathBuf::from("/dev/sda2"),
+                    target: PathBuf::from("/mnt"),
+                },
+                Mount {
+                    source: PathBuf::from("/dev/sda1"),
+                    target: PathBuf::from("/media/data"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unescapes_mount_paths() {
+        assert_eq!(unescape_mount_path("/media/my\\040card"), "/media/my card");
+        assert_eq!(unescape_mount_path("/a\\134b\\011"), "/a\\b\t");
+        assert_eq!(unescape_mount_path("/mnt"), "/mnt");
+    }
+
     fn load_device_test(src: impl AsRef<Path>) -> DeviceTestCase {
         DeviceTestCase {
             device_type: match read_to_string(src.as_ref().join("scribe_type"))
@@ -383,17 +1251,24 @@ mod tests {
                 "ExternalDrive" => DeviceType::ExternalDrive,
                 "CDROM" => DeviceType::CDROM,
                 "LoopBack" => DeviceType::LoopBack,
+                "VirtualDrive" => DeviceType::VirtualDrive,
+                "Raid" => DeviceType::Raid,
+                "DeviceMapper" => DeviceType::DeviceMapper,
+                "RamDisk" => DeviceType::RamDisk,
+                "NetworkBlock" => DeviceType::NetworkBlock,
                 v => panic!("not a valid device type: {}", v),
             },
             flags: read_to_string(src.as_ref().join("scribe_flags"))
                 .unwrap()
                 .lines()
-                .filter(|x| x.trim().len() > 0)
+                .filter(|x| !x.trim().is_empty())
                 .map(|x| match x {
                     "Mounted" => Flags::Mounted,
                     "ZeroSize" => Flags::ZeroSize,
                     "ReadOnly" => Flags::ReadOnly,
                     "Large" => Flags::Large,
+                    "TooSmall" => Flags::TooSmall,
+                    "InUse" => Flags::InUse,
                     v => panic!("nor a valid flag: {}", v),
                 })
                 .collect(),
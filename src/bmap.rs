@@ -0,0 +1,417 @@
+use checksum::from_hex;
+use failure::Error;
+use libc;
+use roxmltree::{Document, Node};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::convert::TryFrom;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Which byte ranges of an image hold data. Everything outside of the ranges is known to be zeros
+/// and does not need to be written to the device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockMap {
+    /// The size of the whole image in bytes.
+    pub len: u64,
+    /// The ranges that hold data, sorted and not overlapping.
+    pub ranges: Vec<Range<u64>>,
+}
+
+impl BlockMap {
+    /// Finds the data in a sparse file by asking the filesystem where its holes are with
+    /// `SEEK_DATA` and `SEEK_HOLE`. Filesystems that do not track holes report the whole file as
+    /// data.
+    pub fn from_holes(file: &File) -> io::Result<BlockMap> {
+        let len = file.metadata()?.len();
+        let fd = file.as_raw_fd();
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let start = match seek(fd, offset, libc::SEEK_DATA)? {
+                Some(start) => start,
+                // Only holes are left.
+                None => break,
+            };
+            let end = seek(fd, start, libc::SEEK_HOLE)?.unwrap_or(len).min(len);
+            ranges.push(start..end);
+            offset = end;
+        }
+        Ok(BlockMap { len, ranges })
+    }
+
+    /// The number of bytes of data in the image.
+    pub fn mapped_len(&self) -> u64 {
+        self.ranges.iter().map(|range| range.end - range.start).sum()
+    }
+
+    /// Returns true if any byte of `range` holds data.
+    pub fn overlaps(&self, range: &Range<u64>) -> bool {
+        // The ranges are sorted so the first one that ends after the start is the only candidate.
+        let index = self.ranges.partition_point(|mapped| mapped.end <= range.start);
+        self.ranges
+            .get(index)
+            .is_some_and(|mapped| mapped.start < range.end)
+    }
+
+    /// Lists the parts of the ranges in this map that are not in `other`.
+    pub fn difference(&self, other: &BlockMap) -> Vec<Range<u64>> {
+        let mut gaps = Vec::new();
+        for range in &self.ranges {
+            let mut start = range.start;
+            let first = other.ranges.partition_point(|covered| covered.end <= range.start);
+            for covered in other.ranges[first..]
+                .iter()
+                .take_while(|covered| covered.start < range.end)
+            {
+                if covered.start > start {
+                    gaps.push(start..covered.start);
+                }
+                start = start.max(covered.end);
+            }
+            if start < range.end {
+                gaps.push(start..range.end);
+            }
+        }
+        gaps
+    }
+
+    /// Adds a range to the end of the map, merging it with the last range if they touch.
+    pub fn push(&mut self, range: Range<u64>) {
+        if let Some(last) = self.ranges.last_mut() {
+            if last.end == range.start {
+                last.end = range.end;
+                return;
+            }
+        }
+        self.ranges.push(range);
+    }
+}
+
+/// A block map from a bmaptool `.bmap` file, which lists the ranges of an image that hold data
+/// along with a SHA256 checksum of each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bmap {
+    pub map: BlockMap,
+    /// The checksum of each range in `map`.
+    pub checksums: Vec<[u8; 32]>,
+}
+
+impl Bmap {
+    /// Looks for a `.bmap` file next to the image the way bmaptool does, trying the full name of
+    /// the image first and then with each of its extensions removed, so `disk.img.xz` finds
+    /// `disk.img.xz.bmap`, `disk.img.bmap` or `disk.bmap`.
+    pub fn find(image: &Path) -> Option<PathBuf> {
+        let mut name = image.file_name()?.to_str()?;
+        loop {
+            let candidate = image.with_file_name(format!("{}.bmap", name));
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+            match name.rfind('.') {
+                Some(dot) if dot > 0 => name = &name[..dot],
+                _ => return None,
+            }
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<Bmap, Error> {
+        Bmap::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a version 2 bmap file. Older versions use SHA1 checksums, which are not supported.
+    pub fn parse(text: &str) -> Result<Bmap, Error> {
+        let doc = Document::parse(text)?;
+        let root = doc.root_element();
+        if !root.has_tag_name("bmap") {
+            bail!("not a bmap file");
+        }
+        let version = root.attribute("version").unwrap_or("");
+        if !version.starts_with("2.") {
+            bail!("bmap version '{}' is not supported, only version 2", version);
+        }
+
+        let checksum_type = child_text(root, "ChecksumType")?;
+        if checksum_type != "sha256" {
+            bail!("'{}' checksums are not supported, only sha256", checksum_type);
+        }
+
+        // The file's own checksum is worked out with the checksum itself replaced by zeros.
+        let file_checksum = child_text(root, "BmapFileChecksum")?;
+        let zeroed = text.replacen(file_checksum, &"0".repeat(file_checksum.len()), 1);
+        if parse_hex(file_checksum)? != <[u8; 32]>::from(Sha256::digest(zeroed.as_bytes())) {
+            bail!("the bmap file is corrupt, its checksum does not match");
+        }
+
+        let len = parse_number(child_text(root, "ImageSize")?)?;
+        let block_size = parse_number(child_text(root, "BlockSize")?)?;
+        if block_size == 0 {
+            bail!("the block size is zero");
+        }
+
+        let mut map = BlockMap {
+            len,
+            ranges: Vec::new(),
+        };
+        let mut checksums = Vec::new();
+        let ranges = root
+            .children()
+            .find(|node| node.has_tag_name("BlockMap"))
+            .ok_or_else(|| format_err!("missing BlockMap"))?;
+        for range in ranges.children().filter(|node| node.has_tag_name("Range")) {
+            let text = range.text().unwrap_or("").trim();
+            let (first, last) = match text.find('-') {
+                Some(dash) => (&text[..dash], &text[dash + 1..]),
+                None => (text, text),
+            };
+            let outside = || format_err!("the block range '{}' is outside the image", text);
+            let start = parse_number(first)?.checked_mul(block_size).ok_or_else(outside)?;
+            let end = parse_number(last)?
+                .checked_add(1)
+                .and_then(|end| end.checked_mul(block_size))
+                .ok_or_else(outside)?
+                .min(len);
+            if start >= end || map.ranges.last().is_some_and(|last| last.end > start) {
+                bail!("the block range '{}' is out of order or outside the image", text);
+            }
+            map.ranges.push(start..end);
+
+            let checksum = range
+                .attribute("chksum")
+                .ok_or_else(|| format_err!("the block range '{}' has no checksum", text))?;
+            checksums.push(parse_hex(checksum)?);
+        }
+        Ok(Bmap { map, checksums })
+    }
+}
+
+/// Checks the data of each range of a `Bmap` against its checksum as the image is read through.
+pub struct RangeChecker<'a> {
+    bmap: &'a Bmap,
+    /// The range currently being read.
+    index: usize,
+    hasher: Sha256,
+}
+
+impl<'a> RangeChecker<'a> {
+    pub fn new(bmap: &'a Bmap) -> RangeChecker<'a> {
+        RangeChecker {
+            bmap,
+            index: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Adds the next `data` of the image, which starts at `offset`. Returns an error as soon as a
+    /// range does not match its checksum.
+    pub fn update(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        while let Some(range) = self.bmap.map.ranges.get(self.index) {
+            if range.start >= end {
+                break;
+            }
+            let from = range.start.max(offset);
+            let to = range.end.min(end);
+            self.hasher
+                .update(&data[(from - offset) as usize..(to - offset) as usize]);
+            if range.end > end {
+                break;
+            }
+
+            let digest = <[u8; 32]>::from(self.hasher.finalize_reset());
+            if digest != self.bmap.checksums[self.index] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the image does not match the checksum in the block map for bytes {} to \
+                         {}",
+                        range.start, range.end
+                    ),
+                ));
+            }
+            self.index += 1;
+        }
+        Ok(())
+    }
+
+    /// Checks that every range was read, failing if the image ended early.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.index < self.bmap.map.ranges.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the image is smaller than its block map",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Result<&'a str, Error> {
+    node.children()
+        .find(|child| child.has_tag_name(name))
+        .and_then(|child| child.text())
+        .map(str::trim)
+        .ok_or_else(|| format_err!("missing {}", name))
+}
+
+fn parse_number(text: &str) -> Result<u64, Error> {
+    text.trim()
+        .parse()
+        .map_err(|_| format_err!("'{}' is not a number", text))
+}
+
+fn parse_hex(text: &str) -> Result<[u8; 32], Error> {
+    from_hex(text.trim())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| format_err!("'{}' is not a SHA256 checksum", text.trim()))
+}
+
+/// Seeks `fd` with `SEEK_DATA` or `SEEK_HOLE`, returning None when there is no more data.
+fn seek(fd: i32, offset: u64, whence: i32) -> io::Result<Option<u64>> {
+    let result = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+    if result < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(result as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use checksum::to_hex;
+    use std::fs::OpenOptions;
+    use util::test_dir;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn finds_holes_in_sparse_files() {
+        let dir = test_dir("sparse");
+        let path = dir.join("image");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(16 << 20).unwrap();
+        file.write_all_at(&[1; 4096], 4 << 20).unwrap();
+        let map = BlockMap::from_holes(&file).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(map.len, 16 << 20);
+        // Filesystems without hole support report everything as data, either way the written
+        // block must be covered.
+        assert!(map.overlaps(&((4 << 20)..(4 << 20) + 1)));
+        assert!(map.mapped_len() >= 4096);
+    }
+
+    #[test]
+    fn checks_overlaps() {
+        let mut map = BlockMap { len: 100, ranges: Vec::new() };
+        map.push(10..20);
+        map.push(20..30);
+        map.push(50..60);
+        assert_eq!(map.ranges, vec![10..30, 50..60]);
+        assert_eq!(map.mapped_len(), 30);
+        assert!(!map.overlaps(&(0..10)));
+        assert!(map.overlaps(&(5..11)));
+        assert!(map.overlaps(&(29..50)));
+        assert!(!map.overlaps(&(30..50)));
+        assert!(map.overlaps(&(55..56)));
+        assert!(!map.overlaps(&(60..100)));
+
+        let written = BlockMap { len: 100, ranges: vec![0..12, 15..20, 55..70] };
+        assert_eq!(map.difference(&written), vec![12..15, 20..30, 50..55]);
+    }
+
+    fn bmap_image() -> Vec<u8> {
+        let mut image = vec![1; 4096];
+        image.extend_from_slice(&[0; 2 * 4096]);
+        image.extend_from_slice(&[3; 4096]);
+        image.extend_from_slice(&[4; 100]);
+        image
+    }
+
+    #[test]
+    fn parses_bmap_files() {
+        let text = include_str!("tests/image.bmap");
+        let bmap = Bmap::parse(text).unwrap();
+        assert_eq!(
+            bmap.map,
+            BlockMap {
+                len: 16484,
+                ranges: vec![0..4096, 12288..16484],
+            }
+        );
+        assert_eq!(bmap.checksums.len(), 2);
+
+        let tampered = text.replace("> 3-4 <", "> 2-4 <");
+        assert!(Bmap::parse(&tampered).is_err());
+    }
+
+    /// Replaces the checksum of a bmap file with the right one for its changed contents.
+    fn fix_file_checksum(text: &str) -> String {
+        let doc = Document::parse(text).unwrap();
+        let checksum = child_text(doc.root_element(), "BmapFileChecksum").unwrap().to_string();
+        let zeroed = text.replace(&checksum, &"0".repeat(checksum.len()));
+        let digest = to_hex(&Sha256::digest(zeroed.as_bytes()));
+        zeroed.replace(&"0".repeat(checksum.len()), &digest)
+    }
+
+    #[test]
+    fn rejects_ranges_that_overflow() {
+        let text = include_str!("tests/image.bmap");
+        for (from, to) in &[
+            ("> 3-4 <", "> 3-18446744073709551615 <"),
+            ("> 3-4 <", "> 4503599627370496-4503599627370497 <"),
+            ("> 4096 <", "> 18446744073709551615 <"),
+        ] {
+            let bogus = fix_file_checksum(&text.replace(from, to));
+            let err = Bmap::parse(&bogus).unwrap_err().to_string();
+            assert!(err.contains("outside the image"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn checks_ranges_while_streaming() {
+        let bmap = Bmap::parse(include_str!("tests/image.bmap")).unwrap();
+        let image = bmap_image();
+
+        let mut checker = RangeChecker::new(&bmap);
+        for (i, piece) in image.chunks(1000).enumerate() {
+            checker.update(i as u64 * 1000, piece).unwrap();
+        }
+        checker.finish().unwrap();
+
+        let mut checker = RangeChecker::new(&bmap);
+        checker.update(0, &image[..5000]).unwrap();
+        assert!(checker.finish().is_err());
+
+        // Unmapped blocks are not checked.
+        let mut changed = image.clone();
+        changed[5000] = 9;
+        RangeChecker::new(&bmap).update(0, &changed).unwrap();
+        changed[13000] = 9;
+        assert!(RangeChecker::new(&bmap).update(0, &changed).is_err());
+    }
+
+    #[test]
+    fn finds_bmap_files_next_to_images() {
+        let dir = test_dir("bmap");
+        let image = dir.join("disk.img.xz");
+        assert_eq!(Bmap::find(&image), None);
+
+        File::create(dir.join("disk.bmap")).unwrap();
+        assert_eq!(Bmap::find(&image), Some(dir.join("disk.bmap")));
+        File::create(dir.join("disk.img.bmap")).unwrap();
+        assert_eq!(Bmap::find(&image), Some(dir.join("disk.img.bmap")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
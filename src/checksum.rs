@@ -0,0 +1,307 @@
+use copy;
+use failure::Error;
+use md5::Md5;
+use progress::Progress;
+use sha2::digest::DynDigest;
+use signature;
+use sha2::{Sha256, Sha512};
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Names of files listing the checksums of every file in a directory, as published by distros.
+const SUMS_FILES: &[(&str, Algorithm)] = &[
+    ("SHA512SUMS", Algorithm::Sha512),
+    ("SHA256SUMS", Algorithm::Sha256),
+    ("MD5SUMS", Algorithm::Md5),
+];
+
+/// The hash algorithms that image checksums can be given in, strongest first.
+#[derive(Debug, PartialEq, Copy, Clone, PartialOrd, Eq, Ord)]
+pub enum Algorithm {
+    Sha512,
+    Sha256,
+    Md5,
+}
+
+/// The checksum an image is expected to have and where it came from.
+#[derive(Debug, PartialEq)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+    /// Where the checksum was found, for messages.
+    pub source: String,
+    /// The file of checksums it came from, None if it was given directly.
+    pub file: Option<PathBuf>,
+}
+
+impl Algorithm {
+    /// Works out the algorithm from the length of a checksum written in hex.
+    fn from_hex_len(len: usize) -> Option<Algorithm> {
+        match len {
+            128 => Some(Algorithm::Sha512),
+            64 => Some(Algorithm::Sha256),
+            32 => Some(Algorithm::Md5),
+            _ => None,
+        }
+    }
+
+    /// Works out the algorithm from the extension of a checksum file such as `.sha256`, or the
+    /// tag at the start of a BSD style checksum line.
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_lowercase().as_str() {
+            "sha512" => Some(Algorithm::Sha512),
+            "sha256" => Some(Algorithm::Sha256),
+            "md5" => Some(Algorithm::Md5),
+            _ => None,
+        }
+    }
+
+    pub fn hasher(self) -> Box<dyn DynDigest> {
+        match self {
+            Algorithm::Sha512 => Box::new(Sha512::default()),
+            Algorithm::Sha256 => Box::new(Sha256::default()),
+            Algorithm::Md5 => Box::new(Md5::default()),
+        }
+    }
+}
+
+impl Expected {
+    /// Reads the `--checksum` argument, which is either the checksum itself in hex or a file of
+    /// checksums with one for `image` in it.
+    pub fn from_arg(arg: &str, image: &Path) -> Result<Expected, Error> {
+        if let (Some(algorithm), Some(digest)) = (Algorithm::from_hex_len(arg.len()), from_hex(arg))
+        {
+            return Ok(Expected {
+                algorithm,
+                digest,
+                source: "the checksum given".to_string(),
+                file: None,
+            });
+        }
+        let path = Path::new(arg);
+        if !path.is_file() {
+            bail!("'{}' is neither a checksum nor a file of checksums", arg);
+        }
+        Expected::from_sums_file(path, image)?.ok_or_else(|| {
+            format_err!(
+                "'{}' does not have a checksum for '{}'",
+                arg,
+                image.display()
+            )
+        })
+    }
+
+    /// Looks for the checksum of `image` in the checksum files next to it. Files named after a
+    /// hash, like `disk.img.sha256`, and the `SHA256SUMS` style files distros publish are tried,
+    /// preferring the strongest hash.
+    pub fn find(image: &Path) -> Result<Option<Expected>, Error> {
+        Ok(Expected::find_all(image)?.into_iter().next())
+    }
+
+    /// Like `find` but prefers checksum files with a detached signature next to them over
+    /// stronger hashes without one, as only a signed checksum file can vouch for the image.
+    pub fn find_signed(image: &Path) -> Result<Option<Expected>, Error> {
+        Ok(Expected::find_all(image)?
+            .into_iter()
+            .min_by_key(|expected| expected.file.as_deref().and_then(signature::find).is_none()))
+    }
+
+    /// Finds every checksum of `image` in the checksum files next to it, in the order `find`
+    /// prefers them.
+    fn find_all(image: &Path) -> Result<Vec<Expected>, Error> {
+        let dir = match image.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut candidates = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+            let algorithm = match SUMS_FILES.iter().find(|sums| sums.0 == name) {
+                Some(sums) => Some(sums.1),
+                None => path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .and_then(Algorithm::from_name),
+            };
+            if let Some(algorithm) = algorithm {
+                candidates.push((algorithm, path));
+            }
+        }
+        // The strongest hash sorts first, and the order is stable for files using the same one.
+        candidates.sort();
+
+        let mut found = Vec::new();
+        for (_, path) in candidates {
+            found.extend(Expected::from_sums_file(&path, image)?);
+        }
+        Ok(found)
+    }
+
+    /// Finds the checksum of `image` in a checksum file. Both the GNU `<hex>  <name>` and BSD
+    /// `SHA256 (<name>) = <hex>` formats are understood. A file with just a checksum in it is
+    /// taken to be for the image when it is named after it, like `disk.img.sha256`.
+    fn from_sums_file(path: &Path, image: &Path) -> Result<Option<Expected>, Error> {
+        let image_name = image.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let named_after_image = path.file_stem().and_then(|stem| stem.to_str()) == Some(image_name);
+        let text = fs::read_to_string(path)?;
+        let source = path.display().to_string();
+
+        for line in text.lines().map(str::trim) {
+            let bsd = line.split_once(" (").and_then(|(tag, rest)| {
+                let (name, hex) = rest.rsplit_once(") = ")?;
+                Some((Algorithm::from_name(tag)?, hex, name))
+            });
+            let (algorithm, hex, name) = if let Some((algorithm, hex, name)) = bsd {
+                (Some(algorithm), hex, Some(name))
+            } else {
+                let mut parts = line.splitn(2, char::is_whitespace);
+                let hex = parts.next().unwrap_or("");
+                let name = parts.next().map(|name| name.trim().trim_start_matches('*'));
+                (None, hex, name)
+            };
+
+            let matches = match name {
+                Some(name) => Path::new(name).file_name() == Some(image_name.as_ref()),
+                None => named_after_image,
+            };
+            let algorithm = algorithm.or_else(|| Algorithm::from_hex_len(hex.len()));
+            if let (true, Some(algorithm), Some(digest)) = (matches, algorithm, from_hex(hex)) {
+                return Ok(Some(Expected {
+                    algorithm,
+                    digest,
+                    source,
+                    file: Some(path.to_path_buf()),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the whole of the file at `path` and returns an error if it does not have the expected
+    /// checksum, updating `progress` as it goes.
+    pub fn check_file(&self, path: &Path, progress: &mut Progress) -> Result<(), Error> {
+        let mut file = File::open(path)?;
+        let mut hasher = self.algorithm.hasher();
+        let mut buf = vec![0; copy::CHUNK_SIZE];
+        loop {
+            let len = copy::read_chunk(&mut file, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+            progress.inc(len as u64);
+        }
+        progress.finish();
+        if *hasher.finalize() != self.digest[..] {
+            bail!(
+                "'{}' does not match the {} checksum from {}.",
+                path.display(),
+                self.algorithm,
+                self.source
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parses a hex string, returning None if it is not valid hex.
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad_integral(
+            true,
+            "",
+            match self {
+                Algorithm::Sha512 => "SHA512",
+                Algorithm::Sha256 => "SHA256",
+                Algorithm::Md5 => "MD5",
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::test_dir;
+    use std::fs::File;
+    use std::io::Write;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    const MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+
+    fn write(path: &Path, text: &str) {
+        File::create(path).unwrap().write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn reads_checksum_arguments() {
+        let image = Path::new("disk.img");
+        let expected = Expected::from_arg(MD5, image).unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Md5);
+        assert_eq!(to_hex(&expected.digest), MD5);
+        assert!(Expected::from_arg("not-a-checksum", image).is_err());
+    }
+
+    #[test]
+    fn finds_checksums_next_to_images() {
+        let dir = test_dir("checksums");
+        let image = dir.join("disk.img.xz");
+        assert_eq!(Expected::find(&image).unwrap(), None);
+
+        write(&dir.join("MD5SUMS"), &format!("{}  other.img.xz\n{} *disk.img.xz\n", SHA256, MD5));
+        let expected = Expected::find(&image).unwrap().unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Md5);
+        assert_eq!(expected.source, dir.join("MD5SUMS").display().to_string());
+
+        // Stronger hashes are preferred, and a bare checksum counts when the file is named after
+        // the image.
+        write(&dir.join("disk.img.xz.sha256"), &format!("{}\n", SHA256));
+        let expected = Expected::find(&image).unwrap().unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Sha256);
+        assert_eq!(to_hex(&expected.digest), SHA256);
+
+        write(
+            &dir.join("SHA512SUMS"),
+            &format!("SHA512 (other.img.xz) = {}{}\n", SHA256, SHA256),
+        );
+        assert_eq!(
+            Expected::find(&image).unwrap().unwrap().algorithm,
+            Algorithm::Sha256
+        );
+        write(
+            &dir.join("SHA512SUMS"),
+            &format!("SHA512 (disk.img.xz) = {}{}\n", SHA256, SHA256),
+        );
+        assert_eq!(
+            Expected::find(&image).unwrap().unwrap().algorithm,
+            Algorithm::Sha512
+        );
+
+        // Only the SHA256SUMS file is signed so it wins over the stronger, unsigned hashes.
+        write(&dir.join("SHA256SUMS"), &format!("{}  disk.img.xz\n", SHA256));
+        write(&dir.join("SHA256SUMS.gpg"), "");
+        let expected = Expected::find_signed(&image).unwrap().unwrap();
+        assert_eq!(expected.file, Some(dir.join("SHA256SUMS")));
+        assert_eq!(
+            Expected::find(&image).unwrap().unwrap().algorithm,
+            Algorithm::Sha512
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use failure::Error;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml;
+
+/// The system wide config file, used when the user does not have one of their own.
+const SYSTEM_CONFIG: &str = "/etc/scribe/config.toml";
+
+/// Settings read from the scribe config file. Every setting is optional so a missing config file
+/// is the same as an empty one.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// A directory of OpenPGP keyrings holding the keys trusted to sign images, used by
+    /// `--verify-signature`. Relative paths are relative to the config file.
+    pub keyring_dir: Option<PathBuf>,
+    /// The file the config was read from, if there was one.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the config from `path` if given, otherwise from the user's config directory or
+    /// failing that the system wide config file. It is not an error for neither to exist.
+    pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Config::default_paths().into_iter().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(Config::default()),
+            },
+        };
+        Config::read(&path)
+            .map_err(|err| format_err!("Could not read the config file '{}': {}", path.display(), err))
+    }
+
+    fn read(path: &Path) -> Result<Config, Error> {
+        let mut config: Config = toml::from_str(&fs::read_to_string(path)?)?;
+        if let (Some(dir), Some(keyring_dir)) = (path.parent(), config.keyring_dir.as_mut()) {
+            *keyring_dir = dir.join(&*keyring_dir);
+        }
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// The places a config file is looked for, in order.
+    fn default_paths() -> Vec<PathBuf> {
+        let user_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        user_dir
+            .map(|dir| dir.join("scribe/config.toml"))
+            .into_iter()
+            .chain(Some(PathBuf::from(SYSTEM_CONFIG)))
+            .collect()
+    }
+
+    /// Describes where the config is, or should be, for messages about missing settings.
+    pub fn describe_path(&self) -> String {
+        match self.path {
+            Some(ref path) => path.display().to_string(),
+            None => Config::default_paths()
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" or "),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use util::test_dir;
+
+    #[test]
+    fn reads_config_files() {
+        let dir = test_dir("config");
+        let path = dir.join("config.toml");
+
+        File::create(&path).unwrap().write_all(b"keyring_dir = \"keys\"\n").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.keyring_dir, Some(dir.join("keys")));
+        assert_eq!(config.path, Some(path.clone()));
+
+        File::create(&path).unwrap().write_all(b"keyring = \"keys\"\n").unwrap();
+        assert!(Config::load(Some(&path)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
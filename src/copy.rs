@@ -0,0 +1,656 @@
+use block_dev::BlockDevice;
+use bmap::{BlockMap, Bmap, RangeChecker};
+use libc;
+use progress::Progress;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The size of each chunk read from the source and written to the destination.
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// The size of the blocks that are counted when reporting differences during verification.
+pub const VERIFY_BLOCK_SIZE: usize = 512;
+/// The size of the blocks that can be left unwritten when writing sparsely.
+pub const SKIP_BLOCK_SIZE: usize = 4096;
+/// The alignment required for buffers used with `O_DIRECT`.
+const DIRECT_ALIGN: usize = 4096;
+/// The ioctl that tells a block device a range of it no longer holds anything, `_IO(0x12, 119)`.
+const BLKDISCARD: libc::c_ulong = 0x1277;
+/// The ioctl that makes a range of a block device read back as zeros, `_IO(0x12, 127)`.
+const BLKZEROOUT: libc::c_ulong = 0x127f;
+/// How many chunks can be waiting for each output of `fan_out` before reading stops to let the
+/// slowest one catch up.
+const FAN_OUT_QUEUE: usize = 4;
+
+/// Something that can be copied from. Compressed sources also report how much of the compressed
+/// data has been read so progress can be based on it.
+pub trait Source: Read {
+    /// The number of compressed bytes read so far and the total number of compressed bytes.
+    fn compressed_read(&self) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+impl Source for File {}
+
+/// Copies everything from `reader` to `writer` in `CHUNK_SIZE` chunks, updating `progress` after
+/// each chunk is written. Returns the number of bytes copied.
+pub fn copy(
+    reader: &mut impl Source,
+    writer: &mut impl Write,
+    progress: &mut Progress,
+) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let len = read_chunk(reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        writer.write_all(&buf[..len])?;
+        written += len as u64;
+        if let Some((read, total)) = reader.compressed_read() {
+            progress.set_read(read, total);
+        }
+        progress.inc(len as u64);
+    }
+    progress.finish();
+    Ok(written)
+}
+
+/// What `copy_sparse` may leave unwritten.
+#[derive(Debug, Default, Clone)]
+pub struct Skip {
+    /// Where the image holds data, blocks outside of it are not written.
+    pub holes: Option<BlockMap>,
+    /// Checksums to check each range of the image against as it is read.
+    pub checksums: Option<Bmap>,
+    /// Also leave out blocks that are all zeros.
+    pub zeros: bool,
+}
+
+impl Skip {
+    /// The parts of an image of `len` bytes that have to read back as they are in the image.
+    /// Nothing outside of a block map is used so the device can keep whatever it held there, but
+    /// holes and zero blocks are part of the image and have to read back as zeros.
+    pub fn needed(&self, len: u64) -> BlockMap {
+        match self.checksums {
+            Some(ref bmap) => bmap.map.clone(),
+            None => {
+                let mut needed = BlockMap {
+                    len,
+                    ranges: Vec::new(),
+                };
+                needed.push(0..len);
+                needed
+            }
+        }
+    }
+}
+
+/// Like `copy` but leaves out the blocks that `skip` says are zeros, writing only the data at the
+/// same offsets in `writer`. Returns a map of what was written. Anything left out keeps whatever
+/// the device held before, so it has to be zeroed with `zero_gaps` afterwards.
+///
+/// When `skip` has checksums each chunk is checked before it is written, stopping at the first
+/// range that does not match.
+pub fn copy_sparse(
+    reader: &mut impl Source,
+    writer: &File,
+    skip: &Skip,
+    progress: &mut Progress,
+) -> io::Result<BlockMap> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = BlockMap {
+        len: 0,
+        ranges: Vec::new(),
+    };
+    let mut checker = skip.checksums.as_ref().map(RangeChecker::new);
+    loop {
+        let len = read_chunk(reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        let offset = written.len;
+        if let Some(ref mut checker) = checker {
+            checker.update(offset, &buf[..len])?;
+        }
+        let mut run: Option<Range<usize>> = None;
+        for (i, block) in buf[..len].chunks(SKIP_BLOCK_SIZE).enumerate() {
+            let start = i * SKIP_BLOCK_SIZE;
+            let end = start + block.len();
+            let absolute = offset + start as u64..offset + end as u64;
+            // A partial block at the end is always written to keep discards aligned.
+            let skipped = block.len() == SKIP_BLOCK_SIZE
+                && (skip.holes.as_ref().is_some_and(|map| !map.overlaps(&absolute))
+                    || (skip.zeros && block.iter().all(|&b| b == 0)));
+            match (skipped, run.as_mut()) {
+                (false, Some(run)) => run.end = end,
+                (false, None) => run = Some(start..end),
+                (true, _) => {
+                    if let Some(run) = run.take() {
+                        write_run(writer, &buf, run, offset, &mut written)?;
+                    }
+                }
+            }
+        }
+        if let Some(run) = run {
+            write_run(writer, &buf, run, offset, &mut written)?;
+        }
+
+        written.len += len as u64;
+        if let Some((read, total)) = reader.compressed_read() {
+            progress.set_read(read, total);
+        }
+        progress.inc(len as u64);
+    }
+    if let Some(checker) = checker {
+        checker.finish()?;
+    }
+    progress.finish();
+    Ok(written)
+}
+
+/// Writes part of the chunk read at `offset` to the same place in `writer`, recording it in
+/// `written`.
+fn write_run(
+    writer: &File,
+    buf: &[u8],
+    run: Range<usize>,
+    offset: u64,
+    written: &mut BlockMap,
+) -> io::Result<()> {
+    writer.write_all_at(&buf[run.clone()], offset + run.start as u64)?;
+    written.push(offset + run.start as u64..offset + run.end as u64);
+    Ok(())
+}
+
+/// Tells the block device `file` that `range` no longer holds anything, which saves the card from
+/// keeping the old data around. What discarded blocks read back as is up to the device, so they
+/// cannot be relied on to be zeros unless it reports that they are.
+pub fn discard(file: &File, range: Range<u64>) -> io::Result<()> {
+    let range = [range.start, range.end - range.start];
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD as _, range.as_ptr()) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Overwrites `range` of `file` with zeros, updating `progress` as it goes.
+pub fn zero(file: &File, range: Range<u64>, progress: &mut Progress) -> io::Result<()> {
+    write_zeros(file, range, progress)?;
+    progress.finish();
+    Ok(())
+}
+
+/// Zeroes the parts of `needed` that `copy_sparse` left out of `written`, so that the holes and
+/// zero blocks of the image do not keep whatever the device held before. `BLKZEROOUT` is used so
+/// the kernel can have the device zero them itself when it supports that, otherwise the kernel
+/// writes the zeros. Files that are not block devices have the zeros written to them directly.
+pub fn zero_gaps(
+    file: &File,
+    needed: &BlockMap,
+    written: &BlockMap,
+    progress: &mut Progress,
+) -> io::Result<()> {
+    for gap in needed.difference(written) {
+        let range = [gap.start, gap.end - gap.start];
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), BLKZEROOUT as _, range.as_ptr()) };
+        if result < 0 {
+            write_zeros(file, gap, progress)?;
+        } else {
+            progress.inc(range[1]);
+        }
+    }
+    progress.finish();
+    Ok(())
+}
+
+fn write_zeros(file: &File, range: Range<u64>, progress: &mut Progress) -> io::Result<()> {
+    let buf = vec![0; CHUNK_SIZE];
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = (range.end - offset).min(CHUNK_SIZE as u64) as usize;
+        file.write_all_at(&buf[..len], offset)?;
+        offset += len as u64;
+        progress.inc(len as u64);
+    }
+    Ok(())
+}
+
+/// Fills `buf` as much as possible from `reader`, only returning less than a full buffer at the
+/// end of the input.
+pub fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// A chunk of data read once by `fan_out` and shared between all of its outputs.
+pub struct Chunk {
+    data: Vec<u8>,
+    /// The number of compressed bytes read and the total there are, as of this chunk.
+    read: Option<(u64, u64)>,
+}
+
+/// Reads from the other end of a `fan_out` output, usually on another thread, so that whatever is
+/// read once can be copied to several places at once.
+pub struct ChunkReader {
+    rx: Receiver<Arc<Chunk>>,
+    chunk: Option<Arc<Chunk>>,
+    pos: usize,
+    read: Option<(u64, u64)>,
+    finished: bool,
+}
+
+/// Makes an output for `fan_out` and the reader for the other end of it.
+pub fn chunk_channel() -> (SyncSender<Arc<Chunk>>, ChunkReader) {
+    let (tx, rx) = mpsc::sync_channel(FAN_OUT_QUEUE);
+    let reader = ChunkReader {
+        rx,
+        chunk: None,
+        pos: 0,
+        read: None,
+        finished: false,
+    };
+    (tx, reader)
+}
+
+/// Reads everything from `reader` once, sending each chunk to all of `outputs`. Outputs whose
+/// reader has gone away, because whatever it was copying to failed, are dropped and the rest carry
+/// on. `on_chunk` is called after each chunk so the caller can show progress. Returns the number
+/// of bytes read.
+///
+/// The end is marked with an empty chunk so that the readers can tell it apart from the reading
+/// failing, which drops the outputs without it.
+pub fn fan_out(
+    reader: &mut impl Source,
+    mut outputs: Vec<SyncSender<Arc<Chunk>>>,
+    mut on_chunk: impl FnMut(),
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let mut data = vec![0; CHUNK_SIZE];
+        let len = read_chunk(reader, &mut data)?;
+        data.truncate(len);
+        let chunk = Arc::new(Chunk {
+            data,
+            read: reader.compressed_read(),
+        });
+        outputs.retain(|output| output.send(chunk.clone()).is_ok());
+        if len == 0 || outputs.is_empty() {
+            break;
+        }
+        total += len as u64;
+        on_chunk();
+    }
+    Ok(total)
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+        if self.chunk.as_ref().is_none_or(|chunk| self.pos == chunk.data.len()) {
+            let chunk = self.rx.recv().map_err(|_| io::Error::other("reading the image failed"))?;
+            if chunk.data.is_empty() {
+                self.finished = true;
+                return Ok(0);
+            }
+            self.read = chunk.read;
+            self.chunk = Some(chunk);
+            self.pos = 0;
+        }
+        let chunk = self.chunk.as_ref().expect("a chunk was just received");
+        let len = buf.len().min(chunk.data.len() - self.pos);
+        buf[..len].copy_from_slice(&chunk.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Source for ChunkReader {
+    fn compressed_read(&self) -> Option<(u64, u64)> {
+        self.read
+    }
+}
+
+/// Flushes all data written to `file` out to `blkdev`. Progress is tracked by watching the number
+/// of sectors the kernel has written to the device since `sectors_before` was recorded. If the
+/// device statistics cannot be read the progress simply stays put until the sync finishes.
+pub fn sync(
+    file: File,
+    blkdev: &BlockDevice,
+    sectors_before: Option<u64>,
+    progress: &mut Progress,
+) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(file.sync_all());
+    });
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(result) => {
+                result?;
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("sync thread exited unexpectedly"))
+            }
+        }
+        if let (Some(before), Ok(now)) = (sectors_before, blkdev.sectors_written()) {
+            let written = now.saturating_sub(before) * 512;
+            let written = progress.total().map_or(written, |total| written.min(total));
+            progress.set(written);
+        }
+    }
+
+    if let (Some(_), Some(total)) = (sectors_before, progress.total()) {
+        progress.set(total);
+    }
+    progress.finish();
+    Ok(())
+}
+
+/// Where and how badly a device differs from the image that was written to it.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    /// The byte offset of the first difference.
+    pub first_offset: u64,
+    /// The number of `VERIFY_BLOCK_SIZE` blocks that differ.
+    pub blocks: u64,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks of {} bytes differ from the image, the first at byte offset {}",
+            self.blocks, VERIFY_BLOCK_SIZE, self.first_offset
+        )
+    }
+}
+
+/// Opens `path` for reading while bypassing the page cache so that what is read back is what
+/// actually made it to the device. Falls back to a normal open and dropping any cached pages if
+/// the device does not support direct IO.
+pub fn open_direct(path: impl AsRef<Path>) -> io::Result<File> {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path.as_ref())
+    {
+        Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            let file = File::open(path)?;
+            unsafe {
+                libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+            }
+            Ok(file)
+        }
+        result => result,
+    }
+}
+
+/// Compares the contents of `image` with the start of `device` chunk by chunk, updating
+/// `progress` as it goes. When `needed` is given only the ranges in it are compared, the rest is
+/// not part of the image. Returns `None` if the whole image matches.
+pub fn verify(
+    image: &mut impl Read,
+    device: &mut (impl Read + Seek),
+    needed: Option<&BlockMap>,
+    progress: &mut Progress,
+) -> io::Result<Option<Mismatch>> {
+    let mut image_buf = vec![0; CHUNK_SIZE];
+    let mut device_buf = AlignedBuf::new(CHUNK_SIZE);
+    let mut offset = 0;
+    let mut mismatch: Option<Mismatch> = None;
+    loop {
+        let len = read_chunk(image, &mut image_buf)?;
+        if len == 0 {
+            break;
+        }
+        // Chunks that are not needed are skipped, so the device has to be seeked to the
+        // right place rather than read straight through.
+        if let Some(needed) = needed {
+            if !needed.overlaps(&(offset..offset + len as u64)) {
+                offset += len as u64;
+                progress.set(offset);
+                continue;
+            }
+            device.seek(SeekFrom::Start(offset))?;
+        }
+
+        // Direct IO needs reads that are a multiple of the block size, so round up and only
+        // compare what came from the image.
+        let device_len = read_chunk(device, &mut device_buf[..round_up(len, DIRECT_ALIGN)])?;
+        if device_len < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "device is smaller than the image",
+            ));
+        }
+
+        for (i, (a, b)) in image_buf[..len]
+            .chunks(VERIFY_BLOCK_SIZE)
+            .zip(device_buf[..len].chunks(VERIFY_BLOCK_SIZE))
+            .enumerate()
+        {
+            let block = offset + (i * VERIFY_BLOCK_SIZE) as u64;
+            if a != b && needed.is_none_or(|needed| needed.overlaps(&(block..block + 1))) {
+                let m = mismatch.get_or_insert(Mismatch {
+                    first_offset: block,
+                    blocks: 0,
+                });
+                m.blocks += 1;
+            }
+        }
+
+        offset += len as u64;
+        progress.set(offset);
+    }
+    progress.finish();
+    Ok(mismatch)
+}
+
+fn round_up(n: usize, multiple: usize) -> usize {
+    n.div_ceil(multiple) * multiple
+}
+
+/// A heap allocated buffer whose start is aligned to `DIRECT_ALIGN`.
+struct AlignedBuf {
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let buf = vec![0; len + DIRECT_ALIGN];
+        let start = buf.as_ptr().align_offset(DIRECT_ALIGN);
+        AlignedBuf { buf, start, len }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use util::test_dir;
+    use std::io::Cursor;
+
+    impl<T: AsRef<[u8]>> Source for Cursor<T> {}
+
+    #[test]
+    fn verify_reports_differences() {
+        let image = vec![1u8; 3 * VERIFY_BLOCK_SIZE + 10];
+        let mut device = image.clone();
+        device.extend_from_slice(&[0; 100]);
+        let mut progress = Progress::new("", image.len() as u64);
+
+        let result = verify(
+            &mut Cursor::new(&image),
+            &mut Cursor::new(&device),
+            None,
+            &mut progress,
+        ).unwrap();
+        assert_eq!(result, None);
+
+        device[VERIFY_BLOCK_SIZE + 5] = 0;
+        device[3 * VERIFY_BLOCK_SIZE + 1] = 0;
+        let result = verify(
+            &mut Cursor::new(&image),
+            &mut Cursor::new(&device),
+            None,
+            &mut progress,
+        ).unwrap();
+        assert_eq!(
+            result,
+            Some(Mismatch {
+                first_offset: VERIFY_BLOCK_SIZE as u64,
+                blocks: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn fan_out_sends_everything_to_each_output() {
+        let image: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let (first_tx, mut first) = chunk_channel();
+        let (second_tx, second) = chunk_channel();
+        let (failed_tx, failed) = chunk_channel();
+        drop(failed);
+
+        let reader = thread::spawn(move || {
+            let mut contents = Vec::new();
+            first.read_to_end(&mut contents).map(|_| contents)
+        });
+        // An output that stops reading part way does not hold up the others.
+        let stopped = thread::spawn(move || {
+            let mut second = second;
+            let mut buf = vec![0; 10];
+            second.read_exact(&mut buf).unwrap();
+        });
+        let mut chunks = 0;
+        let total = fan_out(
+            &mut Cursor::new(&image),
+            vec![first_tx, second_tx, failed_tx],
+            || chunks += 1,
+        ).unwrap();
+        stopped.join().unwrap();
+        assert_eq!(total, image.len() as u64);
+        assert_eq!(chunks, 3);
+        assert_eq!(reader.join().unwrap().unwrap(), image);
+
+        // Without the end of the data being sent the reader reports an error.
+        let (tx, mut reader) = chunk_channel();
+        drop(tx);
+        assert!(reader.read(&mut [0; 10]).is_err());
+    }
+
+    #[test]
+    fn copy_sparse_leaves_out_zeros() {
+        let mut image = vec![0u8; 4 * SKIP_BLOCK_SIZE + 100];
+        image[SKIP_BLOCK_SIZE + 1] = 1;
+        image[4 * SKIP_BLOCK_SIZE + 99] = 2;
+
+        let dir = test_dir("copy-sparse");
+        let path = dir.join("device");
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        device.write_all_at(&vec![0xff; 6 * SKIP_BLOCK_SIZE], 0).unwrap();
+
+        // The last block is partial so is written even though the map says it holds nothing.
+        let block = SKIP_BLOCK_SIZE as u64;
+        let skip = Skip {
+            holes: Some(BlockMap {
+                len: image.len() as u64,
+                ranges: vec![0..2 * block, 3 * block..4 * block],
+            }),
+            checksums: None,
+            zeros: true,
+        };
+        let mut progress = Progress::new("", image.len() as u64);
+        let written =
+            copy_sparse(&mut Cursor::new(&image), &device, &skip, &mut progress).unwrap();
+        assert_eq!(
+            written,
+            BlockMap {
+                len: image.len() as u64,
+                ranges: vec![block..2 * block, 4 * block..image.len() as u64],
+            }
+        );
+
+        let mut contents = Vec::new();
+        (&device).read_to_end(&mut contents).unwrap();
+        assert_eq!(&contents[..SKIP_BLOCK_SIZE], &[0xff; SKIP_BLOCK_SIZE][..]);
+        assert_eq!(
+            &contents[SKIP_BLOCK_SIZE..2 * SKIP_BLOCK_SIZE],
+            &image[SKIP_BLOCK_SIZE..2 * SKIP_BLOCK_SIZE]
+        );
+        assert_eq!(&contents[4 * SKIP_BLOCK_SIZE..image.len()], &image[4 * SKIP_BLOCK_SIZE..]);
+
+        // Until the blocks that were left out are zeroed the device does not match the image.
+        let needed = skip.needed(written.len);
+        let result = verify(
+            &mut Cursor::new(&image),
+            &mut Cursor::new(&contents),
+            Some(&needed),
+            &mut progress,
+        ).unwrap();
+        assert_eq!(result.map(|m| m.first_offset), Some(0));
+
+        zero_gaps(&device, &needed, &written, &mut progress).unwrap();
+        let mut contents = Vec::new();
+        (&device).seek(SeekFrom::Start(0)).unwrap();
+        (&device).read_to_end(&mut contents).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&contents[..image.len()], &image[..]);
+        assert_eq!(&contents[image.len()..], &vec![0xff; 6 * SKIP_BLOCK_SIZE - image.len()][..]);
+        let result = verify(
+            &mut Cursor::new(&image),
+            &mut Cursor::new(&contents),
+            Some(&needed),
+            &mut progress,
+        ).unwrap();
+        assert_eq!(result, None);
+    }
+}
@@ -0,0 +1,176 @@
+use libc;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often `Watcher::wait` returns even if nothing has happened, so that changes nothing reports
+/// such as the terminal being resized are noticed. The devices are only rescanned this often when
+/// the uevent socket could not be opened, as that is then the only way changes are noticed.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The multicast group the kernel sends uevents to.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// What happened while waiting in `Watcher::wait`.
+#[derive(Debug, Default)]
+pub struct Event {
+    /// There is input waiting to be read.
+    pub input: bool,
+    /// Block devices may have been added, removed or changed and should be rescanned.
+    pub devices: bool,
+}
+
+/// Watches for block devices being added or removed using the kernel's uevent netlink socket,
+/// and for filesystems being mounted or unmounted. Falls back to periodic rescans when the socket
+/// is not available.
+pub struct Watcher {
+    socket: Option<RawFd>,
+    /// The mount table, which reports a change when polled whenever something is mounted or
+    /// unmounted. Mounting does not generate uevents so it is watched as well.
+    mounts: Option<File>,
+    last_rescan: Instant,
+}
+
+impl Watcher {
+    /// Creates a watcher that watches `mounts_file`, normally `/proc/self/mountinfo`, for
+    /// filesystems being mounted or unmounted.
+    pub fn new(mounts_file: &Path) -> Watcher {
+        Watcher {
+            socket: open_uevent_socket().ok(),
+            mounts: File::open(mounts_file).ok(),
+            last_rescan: Instant::now(),
+        }
+    }
+
+    /// Waits until either `input` is readable or the block devices may have changed.
+    pub fn wait(&mut self, input: RawFd) -> io::Result<Event> {
+        let mut fds = vec![libc::pollfd {
+            fd: input,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        if let Some(socket) = self.socket {
+            fds.push(libc::pollfd {
+                fd: socket,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        if let Some(ref mounts) = self.mounts {
+            fds.push(libc::pollfd {
+                fd: mounts.as_raw_fd(),
+                events: libc::POLLPRI,
+                revents: 0,
+            });
+        }
+
+        let timeout = match self.socket {
+            Some(_) => RESCAN_INTERVAL,
+            None => RESCAN_INTERVAL
+                .checked_sub(self.last_rescan.elapsed())
+                .unwrap_or_default(),
+        };
+        let timeout = timeout.as_secs() as i32 * 1000 + timeout.subsec_millis() as i32;
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(Event::default());
+            }
+            return Err(err);
+        }
+
+        let mut event = Event {
+            // A closed or hung up input is reported as input too, so that reading it finds out.
+            input: fds[0].revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0,
+            devices: false,
+        };
+        for fd in &fds[1..] {
+            if Some(fd.fd) == self.socket && fd.revents & libc::POLLIN != 0 {
+                event.devices |= self.drain_uevents();
+            } else if fd.fd != input && fd.revents & (libc::POLLPRI | libc::POLLERR) != 0 {
+                event.devices = true;
+            }
+        }
+        // Without the uevent socket the devices have to be rescanned every so often instead.
+        if self.socket.is_none() && self.last_rescan.elapsed() >= RESCAN_INTERVAL {
+            event.devices = true;
+        }
+        if event.devices {
+            self.last_rescan = Instant::now();
+        }
+        Ok(event)
+    }
+
+    /// Reads all pending uevents, returning true if any of them were for block devices.
+    fn drain_uevents(&mut self) -> bool {
+        let socket = match self.socket {
+            Some(socket) => socket,
+            None => return false,
+        };
+        let mut buf = [0u8; 8192];
+        let mut block = false;
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    socket,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if len <= 0 {
+                break;
+            }
+            // Each uevent is a header followed by null separated KEY=value pairs.
+            block |= buf[..len as usize]
+                .split(|&b| b == 0)
+                .any(|field| field == b"SUBSYSTEM=block");
+        }
+        block
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket {
+            unsafe {
+                libc::close(socket);
+            }
+        }
+    }
+}
+
+fn open_uevent_socket() -> io::Result<RawFd> {
+    let socket = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if socket < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_UEVENT_GROUP;
+    let res = unsafe {
+        libc::bind(
+            socket,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(socket);
+        }
+        return Err(err);
+    }
+    Ok(socket)
+}
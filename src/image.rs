@@ -0,0 +1,751 @@
+use bmap::BlockMap;
+use bzip2::read::MultiBzDecoder;
+use checksum::Algorithm;
+use copy::Source;
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use sha2::digest::DynDigest;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+use zstd;
+
+/// File extensions of entries in a zip archive that are likely to be disk images.
+const IMAGE_EXTENSIONS: &[&str] = &["img", "iso", "raw", "bin"];
+
+/// Suffixes of images which were compressed before being put in a zip archive.
+const COMPRESSED_SUFFIXES: &[&str] = &[".gz", ".xz", ".zst", ".bz2"];
+
+/// The magic number at the start of every zstd frame.
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+
+/// Skippable zstd frames, which hold metadata rather than data, start with any of these.
+const ZSTD_SKIPPABLE_MAGIC: RangeInclusive<u32> = 0x184D_2A50..=0x184D_2A5F;
+
+/// The compression format of an image, detected from the magic bytes at the start of the file.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+    /// Raw deflate data, which is only found inside zip archives.
+    Deflate,
+}
+
+/// An OS image on disk which may be compressed or stored inside a zip archive.
+#[derive(Debug)]
+pub struct Image {
+    path: PathBuf,
+    /// The name of the image inside the zip archive, if the image is in one.
+    entry: Option<String>,
+    compression: Compression,
+    /// Where the image data starts in the file, non zero for images inside zip archives.
+    offset: u64,
+    /// The size of the image data in the file, which is the compressed size for compressed
+    /// images.
+    data_len: u64,
+    /// The size of the image once decompressed, if known.
+    size: Option<u64>,
+}
+
+/// Reads the decompressed contents of an image while keeping track of how much of the file on
+/// disk has been consumed.
+pub struct ImageReader {
+    inner: Box<dyn Read>,
+    consumed: Rc<Cell<u64>>,
+    /// The size of the compressed file, or None if it is not compressed.
+    compressed_len: Option<u64>,
+    /// Works out the checksum of the image file as it is read.
+    hasher: Option<SharedHasher>,
+    path: PathBuf,
+    offset: u64,
+}
+
+/// A hasher fed from inside the reader chain while the result is taken from outside of it.
+type SharedHasher = Rc<RefCell<Box<dyn DynDigest>>>;
+
+/// Counts the bytes read through it into a shared counter, also hashing them if there is a hasher.
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+    hasher: Option<SharedHasher>,
+}
+
+impl Image {
+    /// Opens an image and detects how it is compressed. For zip archives `entry` names the image
+    /// inside the archive to use, and can be left out if the archive only contains one image.
+    pub fn open(path: impl AsRef<Path>, entry: Option<&str>) -> io::Result<Image> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+        let file_len = file.metadata()?.len();
+
+        if is_zip(&mut file)? {
+            return Image::open_zip_entry(path, file, entry);
+        }
+
+        let mut magic = [0; 6];
+        let magic_len = read_prefix(&mut file, &mut magic)?;
+        let compression = Compression::detect(&magic[..magic_len]);
+
+        let size = uncompressed_size(&mut file, compression, 0, file_len);
+
+        Ok(Image {
+            path,
+            entry: None,
+            compression,
+            offset: 0,
+            data_len: file_len,
+            size,
+        })
+    }
+
+    fn open_zip_entry(path: PathBuf, file: File, entry: Option<&str>) -> io::Result<Image> {
+        let mut archive = ZipArchive::new(file)?;
+        let name = match entry {
+            Some(name) => name.to_string(),
+            None => {
+                let mut candidates = image_entries(&archive);
+                match candidates.len() {
+                    0 => return Err(invalid("the zip archive does not contain any files")),
+                    1 => candidates.remove(0),
+                    _ => {
+                        return Err(invalid(&format!(
+                            "the zip archive contains several images ({}), choose one with \
+                             --entry",
+                            candidates.join(", ")
+                        )))
+                    }
+                }
+            }
+        };
+
+        let mut found = None;
+        for index in 0..archive.len() {
+            let zip_file = archive.by_index_raw(index)?;
+            if zip_file.name() == name {
+                found = Some((
+                    zip_file.header_start(),
+                    zip_file.data_start(),
+                    zip_file.compressed_size(),
+                    zip_file.size(),
+                ));
+                break;
+            }
+        }
+        let (header_start, offset, data_len, size) = found.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' was not found in the zip archive", name),
+            )
+        })?;
+
+        // Read the flags and compression method straight from the local header as the zip crate
+        // only knows about the methods it was built to decompress.
+        let mut header = [0; 10];
+        let mut file = archive.into_inner();
+        file.seek(SeekFrom::Start(header_start))?;
+        file.read_exact(&mut header)?;
+        if header[6] & 1 != 0 {
+            return Err(invalid(&format!("'{}' is encrypted", name)));
+        }
+        let compression = match u16::from(header[8]) | u16::from(header[9]) << 8 {
+            0 => Compression::None,
+            8 => Compression::Deflate,
+            12 => Compression::Bzip2,
+            93 => Compression::Zstd,
+            95 => Compression::Xz,
+            method => {
+                return Err(invalid(&format!(
+                    "'{}' uses an unsupported zip compression method ({})",
+                    name, method
+                )))
+            }
+        };
+
+        let mut image = Image {
+            path,
+            entry: Some(name.clone()),
+            compression,
+            offset,
+            data_len,
+            size: Some(size),
+        };
+
+        // Images are often compressed before they are put in an archive, such as the
+        // *.img.xz files in some zip downloads, so look at the data in the entry as well.
+        let mut magic = [0; 6];
+        let magic_len = read_up_to(&mut image.reader()?, &mut magic)?;
+        match Compression::detect(&magic[..magic_len]) {
+            Compression::None => {}
+            inner if compression == Compression::None => {
+                image.compression = inner;
+                image.size = uncompressed_size(&mut file, inner, offset, data_len);
+            }
+            inner => {
+                return Err(invalid(&format!(
+                    "'{}' is {} compressed inside the zip archive, extract it from the archive \
+                     first",
+                    name, inner
+                )))
+            }
+        }
+        Ok(image)
+    }
+
+    /// The name of the image inside the zip archive, if the image is in one.
+    pub fn entry(&self) -> Option<&str> {
+        self.entry.as_deref()
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// The size of the image once decompressed. This is `None` for compressed formats that do not
+    /// record it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Finds where the data is when the image is an uncompressed sparse file. Returns None for
+    /// compressed images and images in zip archives, whose holes cannot be found without reading
+    /// them.
+    pub fn holes(&self) -> io::Result<Option<BlockMap>> {
+        if self.compression != Compression::None || self.entry.is_some() {
+            return Ok(None);
+        }
+        BlockMap::from_holes(&File::open(&self.path)?).map(Some)
+    }
+
+    /// Opens a new reader over the decompressed contents of the image.
+    pub fn reader(&self) -> io::Result<ImageReader> {
+        self.open_reader(None)
+    }
+
+    /// Like `reader` but also works out the checksum of the image file, as it is on disk, while
+    /// it is read. The result is returned by `ImageReader::finish_checksum`.
+    pub fn checksum_reader(&self, algorithm: Algorithm) -> io::Result<ImageReader> {
+        let hasher = Rc::new(RefCell::new(algorithm.hasher()));
+        // The start of a zip archive, before the image in it, is part of the file too.
+        let mut start = File::open(&self.path)?.take(self.offset);
+        hash_all(&mut start, &hasher)?;
+        self.open_reader(Some(hasher))
+    }
+
+    fn open_reader(&self, hasher: Option<SharedHasher>) -> io::Result<ImageReader> {
+        let consumed = Rc::new(Cell::new(0));
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let file = CountingReader {
+            inner: file.take(self.data_len),
+            count: consumed.clone(),
+            hasher: hasher.clone(),
+        };
+        let inner: Box<dyn Read> = match self.compression {
+            Compression::None => Box::new(file),
+            Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(file)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+            Compression::Bzip2 => Box::new(MultiBzDecoder::new(file)),
+            Compression::Deflate => Box::new(DeflateDecoder::new(file)),
+        };
+        let compressed_len = match self.compression {
+            Compression::None => None,
+            _ => Some(self.data_len),
+        };
+        Ok(ImageReader {
+            inner,
+            consumed,
+            compressed_len,
+            hasher,
+            path: self.path.clone(),
+            offset: self.offset,
+        })
+    }
+}
+
+impl ImageReader {
+    /// Finishes working out the checksum of the image file, hashing whatever is left of the file
+    /// after what has been read, such as the end of a zip archive. Returns None if the reader was
+    /// not opened with `Image::checksum_reader`.
+    pub fn finish_checksum(self) -> io::Result<Option<Vec<u8>>> {
+        let hasher = match self.hasher {
+            Some(hasher) => hasher,
+            None => return Ok(None),
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset + self.consumed.get()))?;
+        hash_all(&mut file, &hasher)?;
+        let digest = hasher.borrow_mut().finalize_reset();
+        Ok(Some(digest.into_vec()))
+    }
+}
+
+/// Feeds everything from `reader` to `hasher`.
+fn hash_all(reader: &mut impl Read, hasher: &SharedHasher) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => hasher.borrow_mut().update(&buf[..len]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl Compression {
+    /// Works out the compression from the first few bytes of a file.
+    pub fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl Read for ImageReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Source for ImageReader {
+    fn compressed_read(&self) -> Option<(u64, u64)> {
+        self.compressed_len.map(|len| (self.consumed.get(), len))
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count.set(self.count.get() + len as u64);
+        if let Some(ref hasher) = self.hasher {
+            hasher.borrow_mut().update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+/// Lists the names of the entries in a zip archive that could be an image to write. Prefers
+/// entries that have a disk image extension, falling back to every file if there are none.
+pub fn zip_images(path: impl AsRef<Path>) -> io::Result<Option<Vec<String>>> {
+    let mut file = File::open(path)?;
+    if !is_zip(&mut file)? {
+        return Ok(None);
+    }
+    Ok(Some(image_entries(&ZipArchive::new(file)?)))
+}
+
+fn image_entries(archive: &ZipArchive<File>) -> Vec<String> {
+    let files: Vec<&str> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .collect();
+    let images: Vec<&str> = files
+        .iter()
+        .cloned()
+        .filter(|name| is_image_name(name))
+        .collect();
+    let mut names: Vec<String> = if images.is_empty() { files } else { images }
+        .into_iter()
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+}
+
+/// Whether the name of a zip entry has a disk image extension, which may be followed by the
+/// extension of a compression format as in `disk.img.xz`.
+fn is_image_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    let name = COMPRESSED_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(&name);
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext))
+        .unwrap_or(false)
+}
+
+fn is_zip(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    let len = read_prefix(file, &mut magic)?;
+    Ok(magic[..len] == *b"PK\x03\x04")
+}
+
+/// Reads as much of `buf` as the file allows from the start of the file.
+fn read_prefix(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(0))?;
+    read_up_to(file, buf)
+}
+
+/// Reads until `buf` is full or the reader runs out, returning how much was read.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Works out the size of the `len` bytes of compressed data at `start` in the file once they are
+/// decompressed, if the compression format records it.
+fn uncompressed_size(
+    file: &mut File,
+    compression: Compression,
+    start: u64,
+    len: u64,
+) -> Option<u64> {
+    match compression {
+        Compression::None => Some(len),
+        Compression::Xz => xz_uncompressed_size(file, start, start + len).ok(),
+        Compression::Zstd => zstd_uncompressed_size(file, start, len).ok().and_then(|s| s),
+        // gzip only records the size modulo 4GiB and bzip2 does not record it at all.
+        Compression::Gzip | Compression::Bzip2 | Compression::Deflate => None,
+    }
+}
+
+/// Reads the uncompressed size from the index at the end of each stream in xz data which runs
+/// from `start` to `end` in the file.
+fn xz_uncompressed_size(file: &mut File, start: u64, mut end: u64) -> io::Result<u64> {
+    let mut total = 0;
+    while end > start {
+        let mut footer = [0; 12];
+        file.seek(SeekFrom::Start(end.checked_sub(12).ok_or_else(|| invalid("truncated"))?))?;
+        file.read_exact(&mut footer)?;
+        // Skip over any stream padding between concatenated streams, which comes in multiples
+        // of four null bytes.
+        if footer[8..] == [0; 4] {
+            end -= 4;
+            continue;
+        }
+        if &footer[10..] != b"YZ" {
+            return Err(invalid("missing xz stream footer"));
+        }
+
+        let backward_size = (u64::from(u32_le(&footer[4..8])) + 1) * 4;
+        let index_start = (end - 12)
+            .checked_sub(backward_size)
+            .ok_or_else(|| invalid("xz index out of bounds"))?;
+        let mut index = vec![0; backward_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+        if index[0] != 0 {
+            return Err(invalid("missing xz index indicator"));
+        }
+
+        let mut pos = 1;
+        let records = read_vli(&index, &mut pos)?;
+        let mut blocks_len = 0;
+        for _ in 0..records {
+            let unpadded = read_vli(&index, &mut pos)?;
+            total += read_vli(&index, &mut pos)?;
+            blocks_len += unpadded.div_ceil(4) * 4;
+        }
+
+        end = index_start
+            .checked_sub(blocks_len + 12)
+            .ok_or_else(|| invalid("xz blocks out of bounds"))?;
+    }
+    Ok(total)
+}
+
+/// Decodes an xz variable length integer.
+fn read_vli(buf: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let byte = *buf.get(*pos).ok_or_else(|| invalid("truncated xz index"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("xz integer too long"))
+}
+
+fn u32_le(buf: &[u8]) -> u32 {
+    u32::from(buf[0])
+        | u32::from(buf[1]) << 8
+        | u32::from(buf[2]) << 16
+        | u32::from(buf[3]) << 24
+}
+
+/// Adds up the content sizes recorded in the headers of every zstd frame, skipping over the blocks
+/// in between, as images compressed by pzstd or concatenated together have several frames.
+/// Skippable frames only hold metadata and are left out. Returns None if any frame does not
+/// record its size. The zstd data is the `len` bytes from `start` in the file.
+fn zstd_uncompressed_size(file: &mut File, start: u64, len: u64) -> io::Result<Option<u64>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file);
+    let mut pos = 0;
+    let mut size = 0u64;
+    while pos < len {
+        let magic = read_le(&mut reader, 4)? as u32;
+        if ZSTD_SKIPPABLE_MAGIC.contains(&magic) {
+            let len = read_le(&mut reader, 4)?;
+            reader.seek_relative(len as i64)?;
+            pos += 8 + len;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Err(invalid("invalid zstd frame header"));
+        }
+
+        let descriptor = read_le(&mut reader, 1)?;
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let window_len = if single_segment { 0 } else { 1 };
+        let dict_id_len = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let size_len = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        reader.seek_relative(window_len + dict_id_len)?;
+        let frame_size = match size_len {
+            // The two byte field leaves out the sizes which fit in one byte.
+            2 => read_le(&mut reader, 2)? + 256,
+            len => read_le(&mut reader, len)?,
+        };
+        size = size
+            .checked_add(frame_size)
+            .ok_or_else(|| invalid("zstd content size too large"))?;
+        pos += 5 + window_len as u64 + dict_id_len as u64 + size_len as u64;
+
+        loop {
+            let header = read_le(&mut reader, 3)?;
+            let block_len = match (header >> 1) & 0x03 {
+                // Raw and compressed blocks are followed by their contents.
+                0 | 2 => header >> 3,
+                // RLE blocks are followed by the single byte to repeat.
+                1 => 1,
+                _ => return Err(invalid("invalid zstd block type")),
+            };
+            reader.seek_relative(block_len as i64)?;
+            pos += 3 + block_len;
+            if header & 0x01 != 0 {
+                break;
+            }
+        }
+        if has_checksum {
+            reader.seek_relative(4)?;
+            pos += 4;
+        }
+    }
+    Ok(Some(size))
+}
+
+/// Reads a little endian integer of `len` bytes, up to 8.
+fn read_le<R: Read>(reader: &mut R, len: usize) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes[..len])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Compression::None => "uncompressed",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+            Compression::Deflate => "deflate",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::io::Write;
+    use util::test_dir;
+    use xz2::write::XzEncoder;
+
+    fn round_trip(name: &str, compressed: Vec<u8>, compression: Compression, size: Option<u64>) {
+        let dir = test_dir(name);
+        let path = dir.join("image");
+        File::create(&path).unwrap().write_all(&compressed).unwrap();
+
+        let image = Image::open(&path, None).unwrap();
+        assert_eq!(image.compression(), compression);
+        assert_eq!(image.size(), size, "{}", name);
+
+        let mut data = Vec::new();
+        let mut reader = image.checksum_reader(Algorithm::Sha256).unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        let checksum = reader.finish_checksum().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data, test_data());
+        assert_eq!(checksum, Some(Sha256::digest(&compressed).to_vec()), "{}", name);
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn decompresses_images() {
+        let data = test_data();
+        let len = data.len() as u64;
+
+        round_trip("raw", data.clone(), Compression::None, Some(len));
+
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&data).unwrap();
+        round_trip("gz", gz.finish().unwrap(), Compression::Gzip, None);
+
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&data).unwrap();
+        let mut xz = xz.finish().unwrap();
+        round_trip("xz", xz.clone(), Compression::Xz, Some(len));
+
+        // Concatenated streams with padding between them
+        let mut second = XzEncoder::new(Vec::new(), 6);
+        second.write_all(&data[..10]).unwrap();
+        xz.extend_from_slice(&[0; 8]);
+        xz.extend(second.finish().unwrap());
+        let dir = test_dir("xz-multi");
+        let path = dir.join("image.xz");
+        File::create(&path).unwrap().write_all(&xz).unwrap();
+        let size = Image::open(&path, None).unwrap().size();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(size, Some(len + 10));
+
+        let zst = zstd::bulk::compress(&data, 3).unwrap();
+        round_trip("zst", zst, Compression::Zstd, Some(len));
+
+        // Two frames with a skippable frame between them, as pzstd writes
+        let half = data.len() / 2;
+        let mut zst = zstd::bulk::compress(&data[..half], 3).unwrap();
+        zst.extend_from_slice(&[0x50, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+        zst.extend(zstd::bulk::compress(&data[half..], 3).unwrap());
+        round_trip("zst-multi", zst.clone(), Compression::Zstd, Some(len));
+
+        // A streamed frame does not record its size
+        let mut first = zstd::bulk::compress(&data[..half], 3).unwrap();
+        let mut second = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+        second.write_all(&data[half..]).unwrap();
+        first.extend(second.finish().unwrap());
+        round_trip("zst-unknown", first, Compression::Zstd, None);
+
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(&data).unwrap();
+        round_trip("bz2", bz.finish().unwrap(), Compression::Bzip2, None);
+    }
+
+    #[test]
+    fn reads_images_from_zip_archives() {
+        use zip::write::{FileOptions, ZipWriter};
+
+        let dir = test_dir("zip");
+        let path = dir.join("images.zip");
+        {
+            let mut zip = ZipWriter::new(File::create(&path).unwrap());
+            let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            zip.start_file("README.txt", options).unwrap();
+            zip.write_all(b"not an image").unwrap();
+            zip.start_file("a.img", options).unwrap();
+            zip.write_all(&test_data()).unwrap();
+            zip.start_file("b.IMG", options).unwrap();
+            zip.write_all(b"another image").unwrap();
+            zip.finish().unwrap();
+        }
+
+        let entries = zip_images(&path).unwrap();
+        assert_eq!(entries, Some(vec!["a.img".to_string(), "b.IMG".to_string()]));
+        assert!(Image::open(&path, None).is_err());
+
+        let image = Image::open(&path, Some("a.img")).unwrap();
+        assert_eq!(image.entry(), Some("a.img"));
+        assert_eq!(image.size(), Some(test_data().len() as u64));
+        let mut data = Vec::new();
+        let mut reader = image.checksum_reader(Algorithm::Md5).unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        // The checksum is of the whole archive, not just the image in it.
+        let checksum = reader.finish_checksum().unwrap();
+        let archive = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data, test_data());
+        assert_eq!(checksum, Some(md5::Md5::digest(&archive).to_vec()));
+    }
+
+    #[test]
+    fn reads_compressed_images_from_zip_archives() {
+        use zip::write::{FileOptions, ZipWriter};
+
+        let xz = |data: &[u8]| {
+            let mut xz = XzEncoder::new(Vec::new(), 6);
+            xz.write_all(data).unwrap();
+            xz.finish().unwrap()
+        };
+        let dir = test_dir("zip-compressed");
+        let path = dir.join("images.zip");
+        {
+            let mut zip = ZipWriter::new(File::create(&path).unwrap());
+            let stored = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            zip.start_file("README.txt", stored).unwrap();
+            zip.write_all(b"not an image").unwrap();
+            zip.start_file("a.img.xz", stored).unwrap();
+            zip.write_all(&xz(&test_data())).unwrap();
+            zip.start_file("b.img.xz", stored).unwrap();
+            zip.write_all(&xz(&xz(&test_data()))).unwrap();
+            zip.finish().unwrap();
+        }
+        // The zip crate is built without any compression, so mark the second entry as xz
+        // compressed by the archive by hand.
+        let header_start = ZipArchive::new(File::open(&path).unwrap())
+            .unwrap()
+            .by_name("b.img.xz")
+            .unwrap()
+            .header_start();
+        let mut archive = fs::read(&path).unwrap();
+        archive[header_start as usize + 8] = 95;
+        fs::write(&path, archive).unwrap();
+
+        let entries = zip_images(&path).unwrap();
+        let image = Image::open(&path, Some("a.img.xz")).unwrap();
+        let twice = Image::open(&path, Some("b.img.xz")).unwrap_err();
+        let mut data = Vec::new();
+        image.reader().unwrap().read_to_end(&mut data).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries, Some(vec!["a.img.xz".to_string(), "b.img.xz".to_string()]));
+        assert_eq!(image.compression(), Compression::Xz);
+        assert_eq!(image.size(), Some(test_data().len() as u64));
+        assert_eq!(data, test_data());
+        assert_eq!(
+            twice.to_string(),
+            "'b.img.xz' is xz compressed inside the zip archive, extract it from the archive first"
+        );
+    }
+}
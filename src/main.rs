@@ -1,74 +1,514 @@
 #[macro_use]
 extern crate failure;
+extern crate bzip2;
+extern crate csv;
+#[macro_use]
+extern crate failure_derive;
+extern crate flate2;
 #[macro_use]
 extern crate human_panic;
 extern crate itertools;
+extern crate libc;
+extern crate serde;
 #[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate structopt;
 //#[macro_use]
 extern crate log;
+extern crate md5;
+extern crate roxmltree;
+extern crate sha2;
 extern crate simplelog;
 extern crate termion;
+extern crate toml;
+extern crate xz2;
+extern crate zip;
+extern crate zstd;
 
-use failure::Error;
+use failure::{Error, Fail};
+use itertools::Itertools;
 use simplelog::{Config, LevelFilter, TermLogger};
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 #[macro_use]
 mod util;
+mod batch;
 mod block_dev;
+mod bmap;
+mod checksum;
+mod config;
+mod copy;
+mod hotplug;
+mod image;
 mod menus;
+mod probe;
+mod progress;
+mod report;
+mod signature;
 
-use block_dev::block_devices;
+use block_dev::Scanner;
+use image::Image;
+use progress::Progress;
 
-/// Returns true is the device should be included in listings
-fn include_dev(blkdev: &block_dev::BlockDevice, show_all: bool) -> bool {
+/// Returns true is the device should be included in listings. Mounted devices are included when
+/// `unmount` is set as they will be unmounted before they are written to.
+fn include_dev(blkdev: &block_dev::BlockDevice, show_all: bool, unmount: bool) -> bool {
     // Filter out all excludable devices (like loopback and cd-roms) as we
     // never want to write to them.
     !blkdev.device_type().is_excluded()
-        && (show_all || blkdev.device_type().is_safe() && blkdev.flags().len() == 0)
+        && (show_all
+            || blkdev.device_type().is_safe()
+                && blkdev
+                    .flags()
+                    .iter()
+                    .all(|flag| unmount && *flag == block_dev::Flags::Mounted))
+}
+
+/// Scans for the devices to offer in the device menu, see `include_dev`. Devices are checked
+/// against the size of the image when it is known. Devices that cannot be read are left out, they
+/// are often in the middle of being plugged in or removed and will show up on the next rescan.
+fn menu_devices(
+    scanner: &Scanner,
+    show_all: bool,
+    unmount: bool,
+    image_len: Option<u64>,
+) -> io::Result<Vec<block_dev::BlockDevice>> {
+    Ok(scanner
+        .block_devices()
+        .map_err(|err| io::Error::other(err.compat()))?
+        .filter_map(Result::ok)
+        .map(|mut dev| {
+            if let Some(image_len) = image_len {
+                dev.check_image_fits(image_len);
+            }
+            dev
+        })
+        .filter(|dev| include_dev(dev, show_all, unmount))
+        .collect())
+}
+
+/// Scans for all block devices, printing a warning for any that cannot be read rather than giving
+/// up on the rest.
+fn scan_devices(scanner: &Scanner) -> Result<Vec<block_dev::BlockDevice>, Error> {
+    let mut devices = Vec::new();
+    for dev in scanner.block_devices()? {
+        match dev {
+            Ok(dev) => devices.push(dev),
+            Err(err) => eprintln!("Warning: skipping a device, {}", err),
+        }
+    }
+    Ok(devices)
+}
+
+/// Unmounts everything mounted from the devices, once the user has agreed to it if `ask` is set.
+/// Returns false if the user declined, in which case nothing is unmounted.
+fn unmount_devices(devices: &[&block_dev::BlockDevice], ask: bool) -> Result<bool, Error> {
+    let mut mounts = Vec::new();
+    for blkdev in devices {
+        let dev_mounts = blkdev.mounts()?;
+        if dev_mounts.is_empty() {
+            continue;
+        }
+        println!("{} is currently mounted:", blkdev.dev_file().display());
+        for mount in &dev_mounts {
+            println!(
+                "  {} on {}",
+                mount.source.display(),
+                mount.target.display()
+            );
+        }
+        mounts.extend(dev_mounts);
+    }
+    if mounts.is_empty() {
+        return Ok(true);
+    }
+    // Unmount the deepest mounts first in case any are nested inside the others.
+    mounts.sort_by_key(|mount| std::cmp::Reverse(mount.target.components().count()));
+
+    if ask && !menus::confirm("Unmount these filesystems?")? {
+        return Ok(false);
+    }
+    for mount in &mounts {
+        block_dev::unmount(mount).map_err(|err| {
+            format_err!("Failed to unmount {}: {}", mount.target.display(), err)
+        })?;
+    }
+    Ok(true)
+}
+
+/// Returns an error unless the scanner reads from the running system. What a snapshot says about a
+/// device has nothing to do with the device file of the same name here, so writing to or reading
+/// from it based on the snapshot could touch the wrong device.
+fn check_live(scanner: &Scanner) -> Result<(), Error> {
+    if !scanner.is_live() {
+        bail!("--sysfs-root, --mounts-file and --swaps-file can only be used with list.");
+    }
+    Ok(())
+}
+
+/// Looks up the block device given on the command line.
+fn find_device(scanner: &Scanner, device: &Path) -> Result<block_dev::BlockDevice, Error> {
+    scanner.block_device(device)
+        .map_err(|err| format_err!("Could not find device '{}': {}", device.display(), err))
+}
+
+/// Lists the reasons why the device is not considered safe to write to. The internal drive
+/// reason is left out when `force_internal` is set and the mounted reason is left out when
+/// `unmount` is set, as the device will be unmounted before it is written to.
+fn unsafe_reasons(
+    blkdev: &block_dev::BlockDevice,
+    force_internal: bool,
+    unmount: bool,
+) -> Vec<&'static str> {
+    let device_type = blkdev.device_type();
+    let mut reasons = Vec::new();
+    if !(force_internal && device_type == block_dev::DeviceType::InternalDrive) {
+        reasons.extend(device_type.unsafe_reason());
+    }
+    reasons.extend(
+        blkdev
+            .flags()
+            .iter()
+            .filter(|flag| !(unmount && **flag == block_dev::Flags::Mounted))
+            .map(|flag| flag.reason()),
+    );
+    reasons
+}
+
+/// Returns an error if the device should not be written to. Devices that are never written to,
+/// such as cd-roms, are always refused while other unsafe devices are only allowed when `force` is
+/// set.
+fn check_writable(
+    blkdev: &block_dev::BlockDevice,
+    force: bool,
+    force_internal: bool,
+    unmount: bool,
+) -> Result<(), Error> {
+    let device_type = blkdev.device_type();
+    if device_type.is_excluded() {
+        bail!(
+            "Refusing to write to {} as it is a {} device.",
+            blkdev.dev_file().display(),
+            device_type
+        );
+    }
+    let reasons = unsafe_reasons(blkdev, force_internal, unmount);
+    if !force && !reasons.is_empty() {
+        bail!(
+            "Refusing to write to {} as {}. Use --force to write to it anyway.",
+            blkdev.dev_file().display(),
+            reasons.join(" and ")
+        );
+    }
+    Ok(())
+}
+
+/// Asks the user to type the device name back before writing to a device that is not considered
+/// safe. Returns true if the write should go ahead.
+fn confirm_unsafe(
+    blkdev: &block_dev::BlockDevice,
+    force_internal: bool,
+    unmount: bool,
+) -> Result<bool, Error> {
+    let reasons = unsafe_reasons(blkdev, force_internal, unmount);
+    if reasons.is_empty() {
+        return Ok(true);
+    }
+
+    println!(
+        "WARNING: {} is not considered safe to write to because:",
+        blkdev.dev_file().display()
+    );
+    for reason in &reasons {
+        println!("  - {}", reason);
+    }
+    println!("All data on this device will be lost.");
+
+    let dev_name = blkdev.dev_name().to_string_lossy();
+    let dev_file = blkdev.dev_file();
+    Ok(menus::confirm_typed(
+        &format!("Type '{}' to continue", dev_name),
+        &[&dev_name, &dev_file.to_string_lossy()],
+    )?)
 }
 
 impl WriteCmd {
-    pub fn run(self) -> Result<(), Error> {
-        check_tty()?;
-
-        let devices = block_dev::block_devices()?
-            .filter(|dev| {
-                dev.as_ref()
-                    .and_then(|dev| Ok(include_dev(dev, self.show_all)))
-                    .unwrap_or(true)
+    pub fn run(self, scanner: &Scanner, config: &config::Config) -> Result<(), Error> {
+        check_live(scanner)?;
+        // Devices given on the command line are written without showing any menus, so scripts
+        // can write images without a TTY.
+        if self.devices.is_empty() {
+            check_tty()?;
+        }
+        self.write(scanner, config)
+    }
+
+    fn write(self, scanner: &Scanner, config: &config::Config) -> Result<(), Error> {
+        let bmap = match self.bmap {
+            Some(ref path) => Some(path.clone()),
+            None if self.no_bmap => None,
+            None => bmap::Bmap::find(&self.image),
+        };
+        let bmap = match bmap {
+            Some(path) => Some(bmap::Bmap::open(&path).map_err(|err| {
+                format_err!("Could not read the block map '{}': {}", path.display(), err)
+            })?),
+            None => None,
+        };
+
+        let expected = match self.checksum {
+            Some(ref arg) => Some(checksum::Expected::from_arg(arg, &self.image)?),
+            None if self.no_checksum => None,
+            None if self.verify_signature => checksum::Expected::find_signed(&self.image)?,
+            None => checksum::Expected::find(&self.image)?,
+        };
+
+        let signed = if self.verify_signature {
+            match check_signature(&self.image, expected.as_ref(), config)? {
+                Some(signed) => signed,
+                None => bail!("Aborted, nothing was written."),
+            }
+        } else {
+            Signed::Nothing
+        };
+        // A signed checksum file only vouches for the image once the image has been checked
+        // against it, which has to happen before any of it is written.
+        if let (Signed::Checksums, Some(expected)) = (signed, expected.as_ref()) {
+            println!(
+                "Checking the image against the signed {} checksum from {}.",
+                expected.algorithm, expected.source
+            );
+            let len = std::fs::metadata(&self.image)?.len();
+            let mut progress = Progress::new("Checking", len);
+            expected.check_file(&self.image, &mut progress).map_err(|err| {
+                format_err!("{} Refusing to write it as it may have been tampered with.", err)
+            })?;
+        }
+
+        if self.discard && !(self.sparse || self.skip_zeros || bmap.is_some()) {
+            bail!(
+                "--discard only makes sense together with --sparse, --skip-zeros or a block map."
+            );
+        }
+
+        let entry = match self.entry {
+            Some(ref entry) => Some(entry.clone()),
+            None => match image::zip_images(&self.image)? {
+                Some(ref entries) if entries.len() > 1 && termion::is_tty(&io::stdin()) => {
+                    match menus::select_from("Select the image in the archive to write", entries) {
+                        None => return Ok(()),
+                        Some(entry) => Some(entry.clone()),
+                    }
+                }
+                _ => None,
+            },
+        };
+        let image = Image::open(&self.image, entry.as_deref())?;
+        let image_len = match (image.size(), bmap.as_ref()) {
+            (Some(len), Some(bmap)) if len != bmap.map.len => bail!(
+                "The image is {} bytes but its block map is for a {} byte image.",
+                len,
+                bmap.map.len
+            ),
+            (len, bmap) => len.or(bmap.map(|bmap| bmap.map.len)),
+        };
+
+        let selected = if self.devices.is_empty() {
+            match menus::select_live_many(
+                "Select devices to write image to",
+                &format!(
+                    "Writing {} ({})",
+                    self.image.display(),
+                    image_len.map_or("unknown size".to_string(), |len| {
+                        block_dev::Size::from_bytes(len).to_string()
+                    })
+                ),
+                scanner.mounts_file(),
+                || menu_devices(scanner, self.show_all, self.unmount, image_len),
+                |dev| dev.dev_file(),
+                device_details,
+                |dev| unsafe_reasons(dev, self.force_internal, self.unmount).is_empty(),
+            )? {
+                None => return Ok(()),
+                Some(devs) => devs,
+            }
+        } else {
+            let mut selected: Vec<block_dev::BlockDevice> = Vec::new();
+            for device in &self.devices {
+                let mut blkdev = find_device(scanner, device)?;
+                if selected.iter().any(|dev| dev.dev_file() == blkdev.dev_file()) {
+                    bail!("{} is given more than once.", blkdev.dev_file().display());
+                }
+                if let Some(image_len) = image_len {
+                    blkdev.check_image_fits(image_len);
+                }
+                selected.push(blkdev);
+            }
+            selected
+        };
+
+        // Every device is checked before any are unmounted, so that nothing is unmounted for a
+        // write that does not go ahead.
+        for blkdev in &selected {
+            if !self.check_device(blkdev, image_len)? {
+                bail!("Aborted, nothing was written.");
+            }
+        }
+        let mut prepared = match self.unmount_selected(scanner, selected)? {
+            Some(prepared) => prepared,
+            None => bail!("Aborted, nothing was written."),
+        };
+
+        if image_len.is_none() {
+            println!(
+                "The uncompressed size of this {} image is not known so it cannot be checked \
+                 that it will fit on the device.",
+                image.compression()
+            );
+        }
+
+        let devices = prepared
+            .iter()
+            .map(|blkdev| format!("'{}'", blkdev.dev_file().display()))
+            .join(", ");
+        let devices = match prepared.len() {
+            1 => format!("device {}", devices),
+            _ => format!("devices {}", devices),
+        };
+        match image.entry() {
+            Some(entry) => println!(
+                "Writing '{}' from '{}' to {}",
+                entry,
+                self.image.display(),
+                devices
+            ),
+            None => println!("Writing '{}' to {}", self.image.display(), devices),
+        }
+
+        let skip = if let Some(bmap) = bmap {
+            println!(
+                "Writing the {} of the image listed in its block map.",
+                block_dev::Size::from_bytes(bmap.map.mapped_len())
+            );
+            Some(copy::Skip {
+                holes: Some(bmap.map.clone()),
+                checksums: Some(bmap),
+                zeros: self.skip_zeros,
+            })
+        } else if self.sparse || self.skip_zeros {
+            let holes = if self.sparse { image.holes()? } else { None };
+            if self.sparse && holes.is_none() {
+                println!(
+                    "Holes can only be found in uncompressed images, use --skip-zeros to leave \
+                     out the zeros in this one."
+                );
+            }
+            Some(copy::Skip {
+                holes,
+                checksums: None,
+                zeros: self.skip_zeros,
             })
-            .collect::<Result<Vec<_>, io::Error>>()?;
-        let selected = match menus::select_from(&devices) {
-            None => return Ok(()),
-            Some(dev) => dev,
+        } else {
+            None
         };
 
-        println!(
-            "Writing '{}' to device '{}'. This will take a while",
-            self.image.display(),
-            selected.dev_file().display()
-        );
+        if let Some(ref expected) = expected {
+            println!(
+                "Checking the image against the {} checksum from {}.",
+                expected.algorithm, expected.source
+            );
+        }
 
-        let mut image_file = File::open(self.image)?;
-        let mut device_file = OpenOptions::new()
-            .write(true)
-            .truncate(false)
-            .open(selected.dev_file())?;
+        if prepared.len() > 1 {
+            return self.write_many(
+                &image,
+                &prepared,
+                image_len,
+                skip.as_ref(),
+                expected.as_ref(),
+                signed,
+            );
+        }
+        let selected = prepared.remove(0);
+        let mut device_file = self.open_device(&selected)?;
 
-        // TODO preform checks:
-        // - check length of image and device
+        let sectors_before = selected.sectors_written().ok();
+        let mut progress = Progress::new("Writing ", image_len);
+        let mut reader = match expected {
+            Some(ref expected) => image.checksum_reader(expected.algorithm)?,
+            None => image.reader()?,
+        };
+        let (copied, written) = match skip {
+            Some(ref skip) => {
+                let written = copy::copy_sparse(&mut reader, &device_file, skip, &mut progress)?;
+                (written.len, Some(written))
+            }
+            None => (copy::copy(&mut reader, &mut device_file, &mut progress)?, None),
+        };
 
-        io::copy(&mut image_file, &mut device_file)?;
+        if let (Some(expected), Some(digest)) = (expected, reader.finish_checksum()?) {
+            if digest != expected.digest {
+                return checksum_mismatch(
+                    &[&selected],
+                    &expected,
+                    &digest,
+                    copied,
+                    signed == Signed::Checksums,
+                );
+            }
+            println!(
+                "The image matches the {} checksum from {}.",
+                expected.algorithm, expected.source
+            );
+        }
 
-        println!("Flushing data. This will take a while");
+        let needed = match (skip.as_ref(), written.as_ref()) {
+            (Some(skip), Some(written)) => {
+                let needed = skip.needed(written.len);
+                // Only devices that promise discarded blocks read back as zeros can skip this.
+                if !(self.discard && selected.discard_zeroes_data()) {
+                    progress.relabel(
+                        "Zeroing ",
+                        needed.mapped_len().saturating_sub(written.mapped_len()),
+                    );
+                    copy::zero_gaps(&device_file, &needed, written, &mut progress)?;
+                }
+                Some(needed)
+            }
+            _ => None,
+        };
 
-        device_file.sync_all()?;
+        progress.relabel(
+            "Flushing",
+            written.as_ref().map_or(copied, bmap::BlockMap::mapped_len),
+        );
+        copy::sync(device_file, &selected, sectors_before, &mut progress)?;
 
+        if !self.no_verify {
+            let mut image_file = image.reader()?;
+            let mut device_file = copy::open_direct(selected.dev_file())?;
+            progress.relabel("Verifying", copied);
+            if let Some(mismatch) =
+                copy::verify(&mut image_file, &mut device_file, needed.as_ref(), &mut progress)?
+            {
+                bail!(
+                    "Verification failed: {}. The device may be faulty or counterfeit.",
+                    mismatch
+                );
+            }
+        }
+
+        if let Some(written) = written {
+            println!(
+                "Left out {} of the {} image as zeros.",
+                block_dev::Size::from_bytes(written.len - written.mapped_len()),
+                block_dev::Size::from_bytes(written.len)
+            );
+        }
         println!(
             "Finished. {} is now safe to remove.",
             selected.dev_file().display()
@@ -76,43 +516,611 @@ impl WriteCmd {
 
         Ok(())
     }
+
+    /// Runs the checks on a device chosen to be written to, asking the user to confirm writing to
+    /// it if it is not considered safe and `--force` was not given. Returns false if the user
+    /// decided not to write to it.
+    fn check_device(
+        &self,
+        selected: &block_dev::BlockDevice,
+        image_len: Option<u64>,
+    ) -> Result<bool, Error> {
+        // Never allow a write that is bound to fail part way through, after the partition table
+        // has already been overwritten.
+        if selected.flags().contains(&block_dev::Flags::TooSmall) {
+            bail!(
+                "The image ({}) is larger than {} ({}).",
+                block_dev::Size::from_bytes(image_len.unwrap_or(0)),
+                selected.dev_file().display(),
+                selected.size()
+            );
+        }
+
+        // Devices given on the command line have not been filtered so must be checked before
+        // asking the user to confirm.
+        if !self.devices.is_empty() {
+            check_writable(selected, self.force, self.force_internal, self.unmount)?;
+        }
+
+        // --force stands in for the typed confirmation so scripts can write to unsafe devices.
+        Ok(self.force || confirm_unsafe(selected, self.force_internal, self.unmount)?)
+    }
+
+    /// Unmounts the selected devices if asked to. Returns the devices as they are now, or None if
+    /// the user decided not to unmount them.
+    fn unmount_selected(
+        &self,
+        scanner: &Scanner,
+        selected: Vec<block_dev::BlockDevice>,
+    ) -> Result<Option<Vec<block_dev::BlockDevice>>, Error> {
+        let mounted = selected
+            .iter()
+            .filter(|blkdev| blkdev.flags().contains(&block_dev::Flags::Mounted))
+            .collect::<Vec<_>>();
+        if !self.unmount || mounted.is_empty() {
+            return Ok(Some(selected));
+        }
+        if !unmount_devices(&mounted, !self.force)? {
+            return Ok(None);
+        }
+
+        let mut unmounted = Vec::new();
+        for blkdev in selected {
+            if !blkdev.flags().contains(&block_dev::Flags::Mounted) {
+                unmounted.push(blkdev);
+                continue;
+            }
+            // Look the device up again to make sure nothing is still mounted.
+            let rechecked = scanner.block_device(blkdev.dev_file())?;
+            if rechecked.flags().contains(&block_dev::Flags::Mounted) {
+                bail!(
+                    "{} is still mounted after unmounting it.",
+                    rechecked.dev_file().display()
+                );
+            }
+            unmounted.push(rechecked);
+        }
+        Ok(Some(unmounted))
+    }
+
+    /// Opens the device for writing, discarding everything on it first if asked to.
+    fn open_device(&self, blkdev: &block_dev::BlockDevice) -> Result<File, Error> {
+        let device_file = OpenOptions::new()
+            .write(true)
+            .truncate(false)
+            .open(blkdev.dev_file())
+            .map_err(|err| format_err!("Could not open {}: {}", blkdev.dev_file().display(), err))?;
+
+        if self.discard {
+            copy::discard(&device_file, 0..blkdev.size().bytes()).map_err(|err| {
+                format_err!(
+                    "Could not discard {}: {}. Leave out --discard to write without it.",
+                    blkdev.dev_file().display(),
+                    err
+                )
+            })?;
+        }
+        Ok(device_file)
+    }
+
+    /// Writes the image to several devices at once, then says which of them failed.
+    fn write_many(
+        &self,
+        image: &Image,
+        devices: &[block_dev::BlockDevice],
+        image_len: Option<u64>,
+        skip: Option<&copy::Skip>,
+        expected: Option<&checksum::Expected>,
+        signed: Signed,
+    ) -> Result<(), Error> {
+        let mut targets = Vec::new();
+        for blkdev in devices {
+            targets.push((blkdev, self.open_device(blkdev)?));
+        }
+        let written = batch::write_all(
+            image,
+            targets,
+            image_len,
+            skip,
+            expected.map(|expected| (expected.algorithm, &expected.digest[..])),
+            self.discard,
+            !self.no_verify,
+        )?;
+
+        if let (Some(expected), Some(digest)) = (expected, written.digest) {
+            if digest != expected.digest {
+                let devices = devices.iter().collect::<Vec<_>>();
+                return checksum_mismatch(
+                    &devices,
+                    expected,
+                    &digest,
+                    written.len,
+                    signed == Signed::Checksums,
+                );
+            }
+            println!(
+                "The image matches the {} checksum from {}.",
+                expected.algorithm, expected.source
+            );
+        }
+
+        if let Some(Ok(Some(map))) = written.results.iter().find(|result| result.is_ok()) {
+            println!(
+                "Left out {} of the {} image as zeros.",
+                block_dev::Size::from_bytes(map.len - map.mapped_len()),
+                block_dev::Size::from_bytes(map.len)
+            );
+        }
+
+        println!("Results:");
+        let mut failed = Vec::new();
+        for (blkdev, result) in devices.iter().zip(&written.results) {
+            match result {
+                Ok(_) => println!("  {}: OK, safe to remove", blkdev.dev_file().display()),
+                Err(err) => {
+                    println!("  {}: FAILED, {}", blkdev.dev_file().display(), err);
+                    failed.push(blkdev.dev_file().display().to_string());
+                }
+            }
+        }
+        if !failed.is_empty() {
+            bail!(
+                "Writing failed on {} of the {} devices: {}.",
+                failed.len(),
+                devices.len(),
+                failed.join(", ")
+            );
+        }
+        println!("Finished. All {} devices are now safe to remove.", devices.len());
+        Ok(())
+    }
 }
 
 impl BackupCmd {
-    pub fn run(self) -> Result<(), Error> {
-        check_tty()?;
+    pub fn run(self, scanner: &Scanner) -> Result<(), Error> {
+        check_live(scanner)?;
+
+        let show_all = self.show_all;
+        // A single argument is the image, as the device can be chosen from the menu instead.
+        let (device, image) = match (self.device, self.image) {
+            (device, Some(image)) => (device, image),
+            (Some(image), None) => (None, image),
+            (None, None) => bail!("Give the name of the image to create."),
+        };
+        // Only the menu needs a TTY, so scripts can back up the device they name.
+        if device.is_none() {
+            check_tty()?;
+        }
+        if image.exists() && !self.force {
+            bail!(
+                "'{}' already exists, use --force to overwrite it.",
+                image.display()
+            );
+        }
+
+        let selected = match device {
+            Some(ref device) => find_device(scanner, device)?,
+            None => match menus::select_live(
+                "Select device to back up",
+                &format!("Backing up to {}", image.display()),
+                scanner.mounts_file(),
+                || menu_devices(scanner, show_all, false, None),
+                |dev| dev.dev_file(),
+                device_details,
+            )? {
+                None => return Ok(()),
+                Some(dev) => dev,
+            },
+        };
+
+        println!(
+            "Backing up device '{}' to '{}'",
+            selected.dev_file().display(),
+            image.display()
+        );
+
+        let mut device_file = File::open(selected.dev_file())?;
+        let mut image_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .create_new(!self.force)
+            .truncate(true)
+            .open(&image)?;
+
+        let mut progress = Progress::new("Reading ", selected.size().bytes());
+        copy::copy(&mut device_file, &mut image_file, &mut progress)?;
+
+        println!("Flushing data");
+        image_file.sync_all()?;
+
+        println!("Finished. Backup saved to '{}'.", image.display());
+
         Ok(())
     }
 }
 
 impl ListCmd {
-    pub fn run(self) -> Result<(), Error> {
-        for disk in block_devices()? {
-            let disk = disk?;
-            if include_dev(&disk, self.show_all) {
-                println!("{}", disk)
+    pub fn run(self, scanner: &Scanner) -> Result<(), Error> {
+        // --reasons lists every device, even those that are never written to.
+        let listed = |disk: &block_dev::BlockDevice| {
+            self.reasons || include_dev(disk, self.show_all, false)
+        };
+        if self.format != report::Format::Text {
+            let devices = scan_devices(scanner)?
+                .iter()
+                .map(|disk| report::DeviceRecord::new(disk, listed(disk)))
+                .collect::<Vec<_>>();
+            return report::write(&mut io::stdout(), self.format, &devices);
+        }
+
+        for disk in scan_devices(scanner)? {
+            if !listed(&disk) {
+                continue;
+            }
+            if self.long {
+                println!("{} \u{2014} {}", disk, describe_contents(&disk)?);
+            } else {
+                println!("{}", disk);
+            }
+            if self.reasons {
+                for reason in explain_unsafe(&disk)? {
+                    println!("    - {}", reason);
+                }
             }
         }
         Ok(())
     }
 }
 
+/// Summarises what is on a device so it can be recognised, ie
+/// `boot (vfat, 256.0MiB), rootfs (ext4, 3.5GiB)`.
+fn describe_contents(blkdev: &block_dev::BlockDevice) -> Result<String, Error> {
+    let partitions = blkdev.partitions()?;
+    if partitions.is_empty() {
+        return Ok(match blkdev.filesystem() {
+            Some(fs) => format!(
+                "{} ({}, no partitions)",
+                fs.label.as_ref().map_or("unlabelled", String::as_str),
+                fs.fs_type
+            ),
+            None => "no partitions".to_string(),
+        });
+    }
+    Ok(partitions
+        .iter()
+        .map(|partition| match partition.filesystem {
+            Some(ref fs) => format!(
+                "{} ({}, {})",
+                fs.label.as_ref().unwrap_or(&partition.name),
+                fs.fs_type,
+                partition.size
+            ),
+            None => format!("{} ({})", partition.name, partition.size),
+        })
+        .join(", "))
+}
+
+/// What a good signature was found on by `check_signature`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Signed {
+    /// The image itself is signed.
+    Image,
+    /// The checksum file is signed, so the image can only be trusted once it has been checked
+    /// against it.
+    Checksums,
+    /// Nothing is signed, or signatures were not asked for.
+    Nothing,
+}
+
+/// Checks that the image has a good signature from a key in the keyring directory set in the
+/// config. A signed checksum file is as good as a signed image as long as the image is checked
+/// against it before anything is written. Returns what was signed, `Signed::Nothing` if the user
+/// chooses to write an unsigned image anyway or None if they do not.
+fn check_signature(
+    image: &Path,
+    expected: Option<&checksum::Expected>,
+    config: &config::Config,
+) -> Result<Option<Signed>, Error> {
+    let keyring_dir = config.keyring_dir.as_ref().ok_or_else(|| {
+        format_err!(
+            "--verify-signature needs keyring_dir to be set in {}.",
+            config.describe_path()
+        )
+    })?;
+
+    let mut problems = Vec::new();
+    let signed = Some((image, Signed::Image))
+        .into_iter()
+        .chain(expected.and_then(|e| e.file.as_deref()).map(|file| (file, Signed::Checksums)));
+    for (signed, what) in signed {
+        let signature = match signature::find(signed) {
+            Some(signature) => signature,
+            None => {
+                problems.push(format!("'{}' has no signature", signed.display()));
+                continue;
+            }
+        };
+        match signature::verify(signed, &signature, keyring_dir) {
+            Ok(message) => {
+                println!("'{}' is signed by a trusted key:", signed.display());
+                for line in message.lines() {
+                    println!("  {}", line);
+                }
+                return Ok(Some(what));
+            }
+            Err(err) => problems.push(format!(
+                "the signature '{}' could not be verified:\n    {}",
+                signature.display(),
+                err.to_string().replace('\n', "\n    ")
+            )),
+        }
+    }
+
+    {
+        use termion::{color, style};
+        eprintln!(
+            "{}{}ERROR: THE IMAGE IS NOT SIGNED BY A TRUSTED KEY{}",
+            style::Bold,
+            color::Fg(color::Red),
+            style::Reset
+        );
+    }
+    for problem in &problems {
+        eprintln!("  - {}", problem);
+    }
+    eprintln!("The image may have been tampered with.");
+    let confirmed = menus::confirm_typed(
+        "Type 'write unsigned' to write it anyway",
+        &["write unsigned"],
+    )?;
+    Ok(if confirmed { Some(Signed::Nothing) } else { None })
+}
+
+/// Reports an image that does not match its checksum as loudly as possible and offers to wipe
+/// what was written of it, so a corrupt image is not mistaken for a good one later on.
+fn checksum_mismatch(
+    devices: &[&block_dev::BlockDevice],
+    expected: &checksum::Expected,
+    actual: &[u8],
+    written: u64,
+    must_wipe: bool,
+) -> Result<(), Error> {
+    use termion::{color, style};
+    eprintln!(
+        "\n{}{}ERROR: THE IMAGE DOES NOT MATCH ITS CHECKSUM{}",
+        style::Bold,
+        color::Fg(color::Red),
+        style::Reset
+    );
+    eprintln!("  expected {} {}", expected.algorithm, checksum::to_hex(&expected.digest));
+    eprintln!("  from     {}", expected.source);
+    eprintln!("  actual   {} {}", expected.algorithm, checksum::to_hex(actual));
+    let names = devices.iter().map(|blkdev| blkdev.dev_file().display().to_string()).join(", ");
+    eprintln!(
+        "The image is corrupt or is not the one the checksum is for. What was written to {} \
+         should not be used.\n",
+        names
+    );
+
+    // When the checksum file is signed the image is known to have been tampered with, so what
+    // was written is never left on the device.
+    let wipe = if must_wipe {
+        println!("The checksum file is signed so {} will be wiped.", names);
+        true
+    } else {
+        menus::confirm(&format!("Wipe what was written to {}?", names))?
+    };
+    if wipe {
+        for blkdev in devices {
+            let device_file = OpenOptions::new().write(true).open(blkdev.dev_file())?;
+            // Discarding is near instant, zeroing what was written is the fallback.
+            if copy::discard(&device_file, 0..blkdev.size().bytes()).is_err() {
+                let mut progress = Progress::new("Wiping  ", written);
+                copy::zero(&device_file, 0..written, &mut progress)?;
+            }
+            device_file.sync_all()?;
+            println!("Wiped {}.", blkdev.dev_file().display());
+        }
+    }
+    bail!(
+        "The image does not match the {} checksum from {}.",
+        expected.algorithm,
+        expected.source
+    );
+}
+
+/// Describes everything known about a device for the details pane of the device picker.
+fn device_details(blkdev: &block_dev::BlockDevice) -> Vec<String> {
+    let mut lines = vec![format!("Device:     {}", blkdev.dev_file().display())];
+    if let Some(vendor) = blkdev.vendor() {
+        lines.push(format!("Vendor:     {}", vendor));
+    }
+    if let Some(model) = blkdev.model() {
+        lines.push(format!("Model:      {}", model));
+    }
+    if let Some(serial) = blkdev.serial() {
+        lines.push(format!("Serial:     {}", serial));
+    }
+    lines.push(format!(
+        "Size:       {} ({} sectors)",
+        blkdev.size(),
+        blkdev.size().0
+    ));
+    lines.push(format!(
+        "Type:       {}{}",
+        blkdev.device_type(),
+        blkdev
+            .transport()
+            .map_or(String::new(), |transport| format!(" over {}", transport))
+    ));
+    lines.push(format!(
+        "Flags:      {}",
+        if blkdev.flags().is_empty() {
+            "none".to_string()
+        } else {
+            blkdev.flags().iter().join(", ")
+        }
+    ));
+
+    match blkdev.mounts() {
+        Ok(ref mounts) if mounts.is_empty() => {}
+        Ok(mounts) => {
+            for mount in mounts {
+                lines.push(format!(
+                    "Mounted:    {} on {}",
+                    mount.source.display(),
+                    mount.target.display()
+                ));
+            }
+        }
+        Err(err) => lines.push(format!("Mounted:    unknown, {}", err)),
+    }
+
+    match blkdev.partitions() {
+        Ok(ref partitions) if partitions.is_empty() => {
+            lines.push("Partitions: none".to_string());
+            if let Some(fs) = blkdev.filesystem() {
+                lines.push(format!("Filesystem: {}", describe_filesystem(&fs)));
+            }
+        }
+        Ok(partitions) => {
+            lines.push("Partitions:".to_string());
+            for partition in partitions {
+                lines.push(format!(
+                    "  {:<12} #{:<3} {:>9}  {}",
+                    partition.name,
+                    partition.number,
+                    partition.size,
+                    partition
+                        .filesystem
+                        .as_ref()
+                        .map_or("unknown".to_string(), describe_filesystem)
+                ));
+            }
+        }
+        Err(err) => lines.push(format!("Partitions: unknown, {}", err)),
+    }
+    lines
+}
+
+/// Describes a filesystem as its type followed by its label and UUID if it has them.
+fn describe_filesystem(fs: &probe::Filesystem) -> String {
+    let mut description = fs.fs_type.to_string();
+    if let Some(ref label) = fs.label {
+        description.push_str(&format!(" \"{}\"", label));
+    }
+    if let Some(ref uuid) = fs.uuid {
+        description.push_str(&format!(" {}", uuid));
+    }
+    description
+}
+
+/// Describes in detail why a device is or is not considered safe to write to.
+fn explain_unsafe(blkdev: &block_dev::BlockDevice) -> Result<Vec<String>, Error> {
+    let device_type = blkdev.device_type();
+    let mut reasons = Vec::new();
+    if let Some(reason) = device_type.unsafe_reason() {
+        if device_type.is_excluded() {
+            reasons.push(format!("{}, these are never written to", reason));
+        } else {
+            reasons.push(reason.to_string());
+        }
+    }
+
+    for flag in blkdev.flags() {
+        match flag {
+            block_dev::Flags::Mounted => {
+                let mounts = blkdev.mounts()?;
+                reasons.push(format!(
+                    "{}: {}",
+                    flag.reason(),
+                    mounts
+                        .iter()
+                        .map(|m| format!("{} on {}", m.source.display(), m.target.display()))
+                        .join(", ")
+                ));
+            }
+            block_dev::Flags::InUse => {
+                let usages = blkdev.usages()?;
+                if usages.is_empty() {
+                    reasons.push(format!(
+                        "{}: something has it open exclusively",
+                        flag.reason()
+                    ));
+                } else {
+                    reasons.push(format!("{}: {}", flag.reason(), usages.iter().join(", ")));
+                }
+            }
+            block_dev::Flags::Large => reasons.push(format!(
+                "it is {} ({} sectors) which is over the {} ({} sectors) limit for devices \
+                 meant for OS images",
+                blkdev.size(),
+                blkdev.size().0,
+                block_dev::LARGE_DEVICE_SIZE,
+                block_dev::LARGE_DEVICE_SIZE.0
+            )),
+            _ => reasons.push(flag.reason().to_string()),
+        }
+    }
+
+    if reasons.is_empty() {
+        reasons.push("it is considered safe to write to".to_string());
+    }
+    Ok(reasons)
+}
+
 fn main() {
     TermLogger::init(LevelFilter::Debug, Config::default()).unwrap();
     setup_panic!();
-    if let Err(err) = match Options::from_args() {
-        Options::Write(c) => c.run(),
-        Options::Backup(c) => c.run(),
-        Options::List(c) => c.run(),
-    } {
-        println!("{}", err)
+    let options = Options::from_args();
+    // Probing the device files only makes sense when they match the devices being scanned.
+    let live = options.sysfs_root == Path::new("/sys/block");
+    let scanner = Scanner::new(options.sysfs_root, options.mounts_file)
+        .swaps_file(options.swaps_file)
+        .probe_devices(live);
+    let cmd = options.cmd;
+    if let Err(err) = config::Config::load(options.config.as_deref()).and_then(|config| match cmd {
+        Command::Write(c) => c.run(&scanner, &config),
+        Command::Backup(c) => c.run(&scanner),
+        Command::List(c) => c.run(&scanner),
+    }) {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "scribe",
             about = "An easy to use image writer for writing raspberry pi images to SD Cards or ISOs to USB drives.")]
-enum Options {
+struct Options {
+    /// Read block devices from this directory instead of /sys/block, such as a snapshot made
+    /// with src/tests/clone_dev.sh
+    #[structopt(long = "sysfs-root", default_value = "/sys/block", parse(from_os_str),
+                raw(hidden = "true"))]
+    sysfs_root: PathBuf,
+
+    /// Read mounted filesystems from this mountinfo file instead of /proc/self/mountinfo
+    #[structopt(long = "mounts-file", default_value = "/proc/self/mountinfo",
+                parse(from_os_str),
+                raw(hidden = "true"))]
+    mounts_file: PathBuf,
+
+    /// Read active swap space from this file instead of /proc/swaps
+    #[structopt(long = "swaps-file", default_value = "/proc/swaps", parse(from_os_str),
+                raw(hidden = "true"))]
+    swaps_file: PathBuf,
+
+    /// Read settings from this file instead of ~/.config/scribe/config.toml or
+    /// /etc/scribe/config.toml
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
     /// Writes an OS image to a device file
     #[structopt(name = "write")]
     Write(WriteCmd),
@@ -132,6 +1140,14 @@ pub struct ListCmd {
     /// List the reasons why a device is considered not safe (implies --show-all)
     #[structopt(short = "r", long = "reasons")]
     reasons: bool,
+    /// Show the partitions on each device and what is on them
+    #[structopt(short = "l", long = "long")]
+    long: bool,
+    /// The output format, one of text, json, csv or tsv. All formats other than text include
+    /// every device along with whether it would be listed
+    #[structopt(long = "format", default_value = "text",
+                raw(possible_values = r#"&["text", "json", "csv", "tsv"]"#))]
+    format: report::Format,
 }
 
 #[derive(Debug, StructOpt)]
@@ -140,7 +1156,11 @@ pub struct BackupCmd {
     #[structopt(short = "a", long = "show-all")]
     show_all: bool,
 
-    /// The device file to read the image from
+    /// Overwrite the image if it already exists
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// The device file to read the image from, chosen from a menu if only IMAGE is given
     #[structopt(name = "DEVICE", parse(from_os_str))]
     device: Option<PathBuf>,
 
@@ -159,13 +1179,68 @@ pub struct WriteCmd {
     #[structopt(long = "force-internal")]
     force_internal: bool,
 
+    /// Allow writing to a DEVICE that is not considered safe, without asking before writing to it
+    /// or before unmounting it with --unmount
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Offer to unmount the device if it is mounted before writing to it
+    #[structopt(short = "u", long = "unmount")]
+    unmount: bool,
+
+    /// Skip reading the device back to check it matches the image after writing.
+    #[structopt(long = "no-verify")]
+    no_verify: bool,
+
+    /// Leave out the holes in a sparse, uncompressed image, having the device zero them itself
+    /// where it can rather than writing zeros over them
+    #[structopt(long = "sparse")]
+    sparse: bool,
+
+    /// Leave out blocks of the image that are all zeros, zeroing them the same way as --sparse
+    #[structopt(long = "skip-zeros")]
+    skip_zeros: bool,
+
+    /// Discard everything on the device before writing to it. The parts of the image left out by
+    /// --sparse or --skip-zeros are still zeroed afterwards unless the device reports that
+    /// discarded blocks read back as zeros
+    #[structopt(long = "discard")]
+    discard: bool,
+
+    /// A bmaptool block map listing the parts of the image to write, by default IMAGE.bmap is used
+    /// if it exists
+    #[structopt(long = "bmap", parse(from_os_str))]
+    bmap: Option<PathBuf>,
+
+    /// Write the whole image even if there is a block map next to it
+    #[structopt(long = "no-bmap", raw(conflicts_with = "\"bmap\""))]
+    no_bmap: bool,
+
+    /// The checksum the image file should have, given in hex or as a file of checksums such as
+    /// SHA256SUMS. By default checksum files next to the image are used
+    #[structopt(long = "checksum")]
+    checksum: Option<String>,
+
+    /// Do not look for checksum files next to the image
+    #[structopt(long = "no-checksum", raw(conflicts_with = "\"checksum\""))]
+    no_checksum: bool,
+
+    /// Refuse to write the image unless it, or the checksum file it is checked against, has a
+    /// detached signature from a key in the keyring directory set in the config
+    #[structopt(long = "verify-signature")]
+    verify_signature: bool,
+
+    /// The image in a zip archive to write, needed when the archive holds several images
+    #[structopt(long = "entry")]
+    entry: Option<String>,
+
     /// The image to write
     #[structopt(name = "IMAGE", parse(from_os_str))]
     image: PathBuf,
 
-    /// The device file to write the image to
+    /// The device files to write the image to. Several devices are written at the same time
     #[structopt(name = "DEVICE", parse(from_os_str))]
-    device: Option<PathBuf>,
+    devices: Vec<PathBuf>,
 }
 
 /// Returns an error if there is no tty attached to both stdin and stderr.
@@ -178,3 +1253,113 @@ fn check_tty() -> Result<(), Error> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::process::Command;
+    use util::test_dir;
+
+    #[test]
+    fn refuses_to_write_or_back_up_from_a_snapshot() {
+        let scanner = Scanner::new("src/tests/sysfs", "src/tests/mountinfo")
+            .swaps_file("src/tests/swaps");
+        let config = config::Config {
+            keyring_dir: None,
+            path: None,
+        };
+        let err = WriteCmd::from_iter(&["write", "disk.img", "sdb"])
+            .run(&scanner, &config)
+            .unwrap_err();
+        assert!(err.to_string().contains("can only be used with list"), "{}", err);
+        let err = BackupCmd::from_iter(&["backup", "sdb", "disk.img"])
+            .run(&scanner)
+            .unwrap_err();
+        assert!(err.to_string().contains("can only be used with list"), "{}", err);
+    }
+
+    #[test]
+    fn checks_every_device_before_unmounting_any() {
+        let dir = test_dir("unmount");
+        let image = dir.join("disk.img");
+        File::create(&image).unwrap().write_all(&[0; 4096]).unwrap();
+
+        // sda is a mounted flash drive that would be unmounted, nvme0n1 is an internal drive.
+        let cmd = WriteCmd::from_iter(&[
+            "write",
+            "--unmount",
+            image.to_str().unwrap(),
+            "sda",
+            "nvme0n1",
+        ]);
+        let scanner = Scanner::new("src/tests/sysfs", "src/tests/mountinfo")
+            .swaps_file("src/tests/swaps");
+        let config = config::Config {
+            keyring_dir: None,
+            path: None,
+        };
+        let err = cmd.write(&scanner, &config).unwrap_err().to_string();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(err.starts_with("Refusing to write to /dev/nvme0n1"), "{}", err);
+    }
+
+    #[test]
+    fn refuses_images_not_matching_a_signed_checksum_file() {
+        Command::new("gpg")
+            .arg("--version")
+            .output()
+            .expect("gpg is needed to sign the checksum file for this test");
+        let dir = test_dir("signed-checksums");
+        let home = dir.join("gnupg");
+        let keys = dir.join("keys");
+        fs::create_dir_all(&keys).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        let gpg = |args: &[&str]| {
+            let status = Command::new("gpg")
+                .env("GNUPGHOME", &home)
+                .args(["--batch", "--quiet", "--passphrase", ""])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        gpg(&["--quick-gen-key", "Scribe Test <test@example.com>", "ed25519", "sign", "never"]);
+        let keyring = keys.join("trusted.gpg");
+        gpg(&["--output", keyring.to_str().unwrap(), "--export"]);
+
+        let image = dir.join("disk.img");
+        File::create(&image).unwrap().write_all(b"tampered").unwrap();
+        let sums = dir.join("SHA256SUMS");
+        File::create(&sums)
+            .unwrap()
+            .write_all(
+                b"9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  disk.img\n",
+            )
+            .unwrap();
+        let signature = dir.join("SHA256SUMS.sig");
+        gpg(&["--output", signature.to_str().unwrap(), "--detach-sign", sums.to_str().unwrap()]);
+        let _ = Command::new("gpgconf")
+            .env("GNUPGHOME", &home)
+            .args(["--kill", "gpg-agent"])
+            .status();
+
+        // The device does not exist, so getting as far as looking it up means the image would
+        // have been written.
+        let cmd = WriteCmd::from_iter(&[
+            "write",
+            "--verify-signature",
+            image.to_str().unwrap(),
+            "scribe-test-missing-card",
+        ]);
+        let scanner = Scanner::new("src/tests/sysfs", "src/tests/mountinfo");
+        let config = config::Config {
+            keyring_dir: Some(keys.clone()),
+            path: None,
+        };
+        let err = cmd.write(&scanner, &config).unwrap_err().to_string();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(err.contains("does not match the SHA256 checksum"), "{}", err);
+    }
+}
@@ -1,10 +1,16 @@
+use hotplug::Watcher;
+use libc;
 use std::fmt::Display;
-use std::io::{stdin, stdout, Write};
-use termion::event::Key;
-use termion::input::TermRead;
+use std::io::{self, stdin, stdout, Write};
+use std::path::{Path, PathBuf};
+use termion::event::{self, Key};
+use termion::screen::AlternateScreen;
 use termion::{self, raw::IntoRawMode};
 
-pub fn select_from<T>(items: &[T]) -> Option<&T>
+/// The file descriptor for stdin.
+const STDIN: i32 = 0;
+
+pub fn select_from<'a, T>(prompt: &str, items: &'a [T]) -> Option<&'a T>
 where
     T: Display,
 {
@@ -14,80 +20,321 @@ where
             None
         }
         _ => {
-            let menu = Menu { items, current: 0 };
-            menu.select()
+            let menu = Menu::new(items.iter().collect());
+            menu.run(prompt, None)
+                .unwrap()
+                .and_then(|mut selected| selected.pop())
         }
     }
 }
 
-struct Menu<'a, T>
+/// Like `select_from` but takes over the whole screen and keeps the items up to date as block
+/// devices are added and removed by calling `refresh` to get the new items. `key` identifies an
+/// item across refreshes so the highlighted item stays the same while others come and go.
+///
+/// The `header` is shown at the top of the screen and the lines from `details` for the
+/// highlighted item are shown below the list. `mounts_file` is watched so the items are refreshed
+/// when filesystems are mounted or unmounted as well.
+pub fn select_live<T, K, F, G, D>(
+    prompt: &str,
+    header: &str,
+    mounts_file: &Path,
+    refresh: F,
+    key: G,
+    details: D,
+) -> io::Result<Option<T>>
+where
+    T: Display,
+    K: PartialEq,
+    F: FnMut() -> io::Result<Vec<T>>,
+    G: Fn(&T) -> K,
+    D: Fn(&T) -> Vec<String>,
+{
+    let selected = run_live(prompt, header, mounts_file, refresh, key, details, None)?;
+    Ok(selected.and_then(|mut selected| selected.pop()))
+}
+
+/// Like `select_live` but lets several items be chosen. Space marks or unmarks the highlighted
+/// item and `a` marks every item that `safe` accepts. Enter returns the marked items, or the
+/// highlighted one if none are marked.
+pub fn select_live_many<T, K, F, G, D, S>(
+    prompt: &str,
+    header: &str,
+    mounts_file: &Path,
+    refresh: F,
+    key: G,
+    details: D,
+    safe: S,
+) -> io::Result<Option<Vec<T>>>
+where
+    T: Display,
+    K: PartialEq,
+    F: FnMut() -> io::Result<Vec<T>>,
+    G: Fn(&T) -> K,
+    D: Fn(&T) -> Vec<String>,
+    S: Fn(&T) -> bool,
+{
+    run_live(prompt, header, mounts_file, refresh, key, details, Some(Box::new(safe)))
+}
+
+fn run_live<'f, T, K, F, G, D>(
+    prompt: &str,
+    header: &str,
+    mounts_file: &Path,
+    refresh: F,
+    key: G,
+    details: D,
+    safe: Option<Safe<'f, T>>,
+) -> io::Result<Option<Vec<T>>>
 where
-    T: 'a,
+    T: Display,
+    K: PartialEq,
+    F: FnMut() -> io::Result<Vec<T>> + 'f,
+    G: Fn(&T) -> K + 'f,
+    D: Fn(&T) -> Vec<String> + 'f,
 {
-    items: &'a [T],
+    let mut refresh = refresh;
+    let menu = Menu::new(refresh()?);
+    menu.run(
+        prompt,
+        Some(Live {
+            header: header.to_string(),
+            mounts_file: mounts_file.to_path_buf(),
+            refresh: Box::new(refresh),
+            same: Box::new(move |a, b| key(a) == key(b)),
+            details: Box::new(details),
+            safe,
+        }),
+    )
+}
+
+/// Rescans the items shown in a live menu.
+type Refresh<'f, T> = Box<dyn FnMut() -> io::Result<Vec<T>> + 'f>;
+/// Tells whether two items are the same so the highlight can follow an item across rescans.
+type Same<'f, T> = Box<dyn Fn(&T, &T) -> bool + 'f>;
+/// Describes an item in more detail than its single line in the list.
+type Details<'f, T> = Box<dyn Fn(&T) -> Vec<String> + 'f>;
+/// Tells whether an item is safe to pick when marking all items at once.
+type Safe<'f, T> = Box<dyn Fn(&T) -> bool + 'f>;
+
+/// How a live menu gets its new items, tells whether two items are the same and describes them.
+struct Live<'f, T> {
+    header: String,
+    mounts_file: PathBuf,
+    refresh: Refresh<'f, T>,
+    same: Same<'f, T>,
+    details: Details<'f, T>,
+    /// Set when several items can be marked, see `select_live_many`.
+    safe: Option<Safe<'f, T>>,
+}
+
+/// How a line of the full screen menu is drawn.
+enum Style {
+    Plain,
+    /// The header and footer, inverted across the whole width of the screen.
+    Bar,
+    /// The highlighted item.
+    Selected,
+    Heading,
+}
+
+/// Asks the user a yes or no question, defaulting to no.
+pub fn confirm(prompt: &str) -> io::Result<bool> {
+    let mut stdout = stdout();
+    write!(stdout, "{} [y/N]: ", prompt)?;
+    stdout.flush()?;
+
+    let mut input = String::new();
+    stdin().read_line(&mut input)?;
+    let input = input.trim().to_lowercase();
+    Ok(input == "y" || input == "yes")
+}
+
+/// Asks the user to type one of the `answers` back to confirm an action. Returns true only if
+/// they did.
+pub fn confirm_typed(prompt: &str, answers: &[&str]) -> io::Result<bool> {
+    let mut stdout = stdout();
+    write!(stdout, "{}: ", prompt)?;
+    stdout.flush()?;
+
+    let mut input = String::new();
+    stdin().read_line(&mut input)?;
+    Ok(answers.contains(&input.trim()))
+}
+
+struct Menu<T> {
+    items: Vec<T>,
     current: usize,
+    /// Which items are marked in a multi-select menu, one for each item.
+    marked: Vec<bool>,
+    /// The number of lines drawn the last time the menu was printed.
+    drawn: usize,
+    /// The first item shown when there are more items than fit on the screen.
+    scroll: usize,
+    /// The size of the terminal the last time the full screen menu was drawn.
+    screen_size: (u16, u16),
 }
 
-impl<'a, T> Menu<'a, T>
+impl<T> Menu<T>
 where
     T: Display,
 {
-    pub fn select(mut self) -> Option<&'a T> {
+    fn new(items: Vec<T>) -> Menu<T> {
+        Menu {
+            marked: vec![false; items.len()],
+            items,
+            current: 0,
+            drawn: 0,
+            scroll: 0,
+            screen_size: (0, 0),
+        }
+    }
+
+    fn run(mut self, prompt: &str, mut live: Option<Live<T>>) -> io::Result<Option<Vec<T>>> {
         let stdout = stdout();
-        let mut stdout = stdout.lock().into_raw_mode().unwrap();
-        let stdin = stdin();
-        let stdin = stdin.lock();
-
-        write!(
-            stdout,
-            "{}Select device to write image to ('q' or 'n' to cancel):\n\r",
-            termion::cursor::Hide,
-        ).unwrap();
-        self.print(&mut stdout);
-        stdout.flush().unwrap();
+        let stdout = stdout.lock().into_raw_mode()?;
+        let mut watcher = live.as_ref().map(|live| Watcher::new(&live.mounts_file));
+
+        // Live menus take over the whole screen, restoring what was there when they are done.
+        let stdout: Box<dyn Write> = if live.is_some() {
+            Box::new(AlternateScreen::from(stdout))
+        } else {
+            Box::new(stdout)
+        };
+        let mut stdout = HiddenCursor::new(stdout)?;
+
+        match live {
+            Some(ref live) => self.draw_screen(&mut stdout, prompt, live)?,
+            None => {
+                write!(stdout, "{} ('q' or 'n' to cancel):\n\r", prompt)?;
+                self.print(&mut stdout)?;
+            }
+        }
+        stdout.flush()?;
 
         let mut selected = None;
 
-        for key in stdin.keys() {
-            match key.unwrap() {
-                Key::Up => {
-                    if self.current > 0 {
-                        self.current -= 1;
-                    }
+        'outer: loop {
+            let (input, devices) = match watcher {
+                Some(ref mut watcher) => {
+                    let event = watcher.wait(STDIN)?;
+                    (event.input, event.devices)
                 }
-                Key::Down => {
-                    if self.current < self.items.len() - 1 {
-                        self.current += 1;
+                None => (true, false),
+            };
+
+            let mut redraw = input;
+            if let Some(live) = live.as_mut() {
+                if devices {
+                    let items = (live.refresh)()?;
+                    redraw |= self.update(items, &*live.same);
+                }
+                redraw |= termion::terminal_size().ok() != Some(self.screen_size);
+            }
+
+            if input {
+                let keys = match read_keys() {
+                    // Nothing more can be read once stdin is closed, so treat it as cancelling.
+                    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break 'outer,
+                    keys => keys?,
+                };
+                for key in keys {
+                    match key {
+                        Key::Up if self.current > 0 => self.current -= 1,
+                        Key::Down if self.current + 1 < self.items.len() => self.current += 1,
+                        Key::Home => self.current = 0,
+                        Key::End => self.current = self.items.len().saturating_sub(1),
+                        Key::Char(' ') if live.as_ref().is_some_and(|l| l.safe.is_some()) => {
+                            if let Some(marked) = self.marked.get_mut(self.current) {
+                                *marked = !*marked;
+                            }
+                        }
+                        Key::Char('a') => {
+                            if let Some(safe) = live.as_ref().and_then(|l| l.safe.as_ref()) {
+                                for (item, marked) in self.items.iter().zip(&mut self.marked) {
+                                    *marked = safe(item);
+                                }
+                            }
+                        }
+                        Key::Char('\n') if !self.items.is_empty() => {
+                            selected = Some(self.take_selected());
+                            break 'outer;
+                        }
+                        Key::Char('q') | Key::Ctrl('c') | Key::Char('n') | Key::Esc => {
+                            break 'outer
+                        }
+                        _ => {}
                     }
                 }
-                Key::Char('\n') => {
-                    selected = Some(&self.items[self.current]);
-                    break;
+            }
+            if redraw {
+                match live {
+                    Some(ref live) => self.draw_screen(&mut stdout, prompt, live)?,
+                    None => self.print(&mut stdout)?,
                 }
-                Key::Char('q') | Key::Ctrl('c') | Key::Char('n') | Key::Esc => break,
-                _ => {}
+                stdout.flush()?;
             }
-            self.reset_and_print(&mut stdout);
-            stdout.flush().unwrap();
-        }
-        write!(
-            stdout,
-            "{}{}\n\r",
-            termion::cursor::Show,
-            termion::style::Reset
-        ).unwrap();
-        stdout.flush().unwrap();
-        selected
+        }
+        if live.is_none() {
+            write!(stdout, "\n\r")?;
+        }
+        stdout.flush()?;
+        Ok(selected)
     }
 
-    fn reset_and_print(&self, w: &mut impl Write) {
-        if self.items.len() > 1 {
-            write!(w, "{}", termion::cursor::Up(self.items.len() as u16 - 1)).unwrap();
+    /// Takes the marked items, or the highlighted one if none are marked.
+    fn take_selected(&mut self) -> Vec<T> {
+        let mut items = self.items.drain(..).zip(self.marked.drain(..)).collect::<Vec<_>>();
+        if !items.iter().any(|(_, marked)| *marked) {
+            return vec![items.swap_remove(self.current).0];
         }
-        self.print(w);
+        items
+            .into_iter()
+            .filter(|(_, marked)| *marked)
+            .map(|(item, _)| item)
+            .collect()
     }
 
-    fn print(&self, w: &mut impl Write) {
+    /// Replaces the items while keeping the same item highlighted, and the same items marked, if
+    /// they are still there. Returns true if anything shown has changed.
+    fn update(&mut self, items: Vec<T>, same: &dyn Fn(&T, &T) -> bool) -> bool {
+        let changed = self.items.len() != items.len()
+            || self.items
+                .iter()
+                .zip(&items)
+                .any(|(a, b)| !same(a, b) || a.to_string() != b.to_string());
+        let current = self.current;
+        let position = self.items
+            .get(self.current)
+            .and_then(|current| items.iter().position(|item| same(item, current)));
+        self.marked = items
+            .iter()
+            .map(|item| {
+                self.items
+                    .iter()
+                    .zip(&self.marked)
+                    .any(|(old, &marked)| marked && same(old, item))
+            })
+            .collect();
+        self.items = items;
+        self.current =
+            position.unwrap_or_else(|| self.current.min(self.items.len().saturating_sub(1)));
+        changed || self.current != current
+    }
+
+    /// Prints the menu, replacing the previously printed one if there was one.
+    fn print(&mut self, w: &mut impl Write) -> io::Result<()> {
+        if self.drawn > 1 {
+            write!(w, "{}", termion::cursor::Up(self.drawn as u16 - 1))?;
+        }
+        write!(w, "\r{}", termion::clear::AfterCursor)?;
+
+        if self.items.is_empty() {
+            write!(w, "  No sutible devices found, waiting for one to be inserted")?;
+            self.drawn = 1;
+            return Ok(());
+        }
+
         for (i, item) in self.items.iter().enumerate() {
             write!(
                 w,
@@ -96,7 +343,171 @@ where
                 if i == self.current { "> " } else { "  " },
                 item,
                 if i == self.items.len() - 1 { "" } else { "\n" },
-            ).unwrap();
+            )?;
+        }
+        self.drawn = self.items.len();
+        Ok(())
+    }
+
+    /// Draws the full screen menu: a header, the list of items, the details of the highlighted
+    /// item and a footer with the keys that can be used.
+    fn draw_screen(&mut self, w: &mut impl Write, prompt: &str, live: &Live<T>) -> io::Result<()> {
+        self.screen_size = termion::terminal_size()?;
+        let (width, height) = (self.screen_size.0 as usize, self.screen_size.1 as usize);
+
+        let multi = live.safe.is_some();
+        let header = match self.marked.iter().filter(|&&marked| marked).count() {
+            marked if multi && marked > 0 => format!(" {} ({} marked)", live.header, marked),
+            _ => format!(" {}", live.header),
+        };
+        let mut lines = vec![
+            (header, Style::Bar),
+            (String::new(), Style::Plain),
+            (prompt.to_string(), Style::Heading),
+        ];
+
+        // Keep the list to half of the screen so there is room for the details.
+        let list_height = self.items.len().min(height.saturating_sub(8) / 2).max(1);
+        if self.current < self.scroll {
+            self.scroll = self.current;
+        } else if self.current >= self.scroll + list_height {
+            self.scroll = self.current + 1 - list_height;
+        }
+        if self.items.is_empty() {
+            lines.push((
+                "  No sutible devices found, waiting for one to be inserted".to_string(),
+                Style::Plain,
+            ));
+        }
+        for (i, item) in self
+            .items
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(list_height)
+        {
+            let mark = match (multi, self.marked[i]) {
+                (false, _) => "",
+                (true, false) => "[ ] ",
+                (true, true) => "[x] ",
+            };
+            if i == self.current {
+                lines.push((format!("> {}{}", mark, item), Style::Selected));
+            } else {
+                lines.push((format!("  {}{}", mark, item), Style::Plain));
+            }
+        }
+
+        if let Some(item) = self.items.get(self.current) {
+            lines.push((String::new(), Style::Plain));
+            lines.push(("Details".to_string(), Style::Heading));
+            for line in (live.details)(item) {
+                lines.push((format!("  {}", line), Style::Plain));
+            }
+        }
+
+        lines.truncate(height.saturating_sub(1));
+        while lines.len() < height.saturating_sub(1) {
+            lines.push((String::new(), Style::Plain));
+        }
+        let keys = if multi {
+            " Up/Down move   Space mark   a mark all safe   Enter select   q cancel"
+        } else {
+            " Up/Down move   Enter select   q cancel"
+        };
+        lines.push((keys.to_string(), Style::Bar));
+
+        for (row, (text, style)) in lines.iter().enumerate() {
+            let mut text = text.chars().take(width).collect::<String>();
+            write!(
+                w,
+                "{}{}",
+                termion::cursor::Goto(1, row as u16 + 1),
+                termion::clear::CurrentLine
+            )?;
+            match style {
+                Style::Plain => write!(w, "{}", text)?,
+                Style::Bar => {
+                    let padding = width.saturating_sub(text.chars().count());
+                    text.extend(::std::iter::repeat_n(' ', padding));
+                    write!(
+                        w,
+                        "{}{}{}",
+                        termion::style::Invert,
+                        text,
+                        termion::style::Reset
+                    )?
+                }
+                Style::Selected => write!(
+                    w,
+                    "{}{}{}",
+                    termion::style::Invert,
+                    text,
+                    termion::style::Reset
+                )?,
+                Style::Heading => write!(
+                    w,
+                    "{}{}{}",
+                    termion::style::Bold,
+                    text,
+                    termion::style::Reset
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hides the cursor while the menu is shown, showing it again when dropped so that it is restored
+/// however the menu is left, including on errors.
+struct HiddenCursor<W: Write>(W);
+
+impl<W: Write> HiddenCursor<W> {
+    fn new(mut w: W) -> io::Result<HiddenCursor<W>> {
+        write!(w, "{}", termion::cursor::Hide)?;
+        Ok(HiddenCursor(w))
+    }
+}
+
+impl<W: Write> Write for HiddenCursor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Drop for HiddenCursor<W> {
+    fn drop(&mut self) {
+        let _ = write!(self.0, "{}{}", termion::cursor::Show, termion::style::Reset);
+        let _ = self.0.flush();
+    }
+}
+
+/// Reads the keys that are waiting on stdin, blocking until there is at least one byte to read.
+/// Returns an `UnexpectedEof` error once stdin has been closed.
+fn read_keys() -> io::Result<Vec<Key>> {
+    let mut buf = [0u8; 64];
+    let len = unsafe { libc::read(STDIN, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(Vec::new());
+        }
+        return Err(err);
+    }
+    if len == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut bytes = buf[..len as usize].iter().map(|&b| Ok(b));
+    let mut keys = Vec::new();
+    while let Some(Ok(byte)) = bytes.next() {
+        if let Ok(event::Event::Key(key)) = event::parse_event(byte, &mut bytes) {
+            keys.push(key);
         }
     }
+    Ok(keys)
 }
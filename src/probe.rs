@@ -0,0 +1,496 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The kinds of filesystem and other signatures that can be recognised on a partition.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FilesystemType {
+    Vfat,
+    Exfat,
+    Ntfs,
+    Ext2,
+    Ext3,
+    Ext4,
+    Iso9660,
+    Swap,
+    Luks,
+}
+
+/// What was found on a partition by looking at its superblock.
+#[derive(Debug, PartialEq)]
+pub struct Filesystem {
+    pub fs_type: FilesystemType,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+}
+
+/// Looks for a known superblock at the start of `dev`. Returns None if nothing was recognised.
+pub fn probe<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    // Order matters, NTFS and exFAT boot sectors also look like FAT ones.
+    for probe in &[luks, iso9660, ext, swap, ntfs, exfat, vfat] {
+        if let Some(fs) = probe(dev)? {
+            return Ok(Some(fs));
+        }
+    }
+    Ok(None)
+}
+
+fn luks<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    let header = match read_at(dev, 0, 512)? {
+        Some(ref header) if header.starts_with(b"LUKS\xba\xbe") => header.clone(),
+        _ => return Ok(None),
+    };
+    let version = u16::from_be_bytes([header[6], header[7]]);
+    Ok(Some(Filesystem {
+        fs_type: FilesystemType::Luks,
+        // Only LUKS2 headers have a label.
+        label: if version >= 2 {
+            text(&header[24..72])
+        } else {
+            None
+        },
+        uuid: text(&header[168..208]),
+    }))
+}
+
+fn iso9660<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    let descriptor = match read_at(dev, 0x8000, 2048)? {
+        Some(ref descriptor) if &descriptor[1..6] == b"CD001" => descriptor.clone(),
+        _ => return Ok(None),
+    };
+    // There is no uuid, the creation time is used in its place like blkid does.
+    let created = &descriptor[813..829];
+    let uuid = if created.iter().all(u8::is_ascii_digit) && created.iter().any(|&b| b != b'0') {
+        let created = String::from_utf8_lossy(created);
+        Some(format!(
+            "{}-{}-{}-{}-{}-{}-{}",
+            &created[0..4],
+            &created[4..6],
+            &created[6..8],
+            &created[8..10],
+            &created[10..12],
+            &created[12..14],
+            &created[14..16]
+        ))
+    } else {
+        None
+    };
+    Ok(Some(Filesystem {
+        fs_type: FilesystemType::Iso9660,
+        label: text(&descriptor[40..72]),
+        uuid,
+    }))
+}
+
+fn ext<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    let sb = match read_at(dev, 1024, 1024)? {
+        Some(ref sb) if sb[56..58] == [0x53, 0xef] => sb.clone(),
+        _ => return Ok(None),
+    };
+    let compat = le32(&sb[92..]);
+    let incompat = le32(&sb[96..]);
+    let ro_compat = le32(&sb[100..]);
+    // Extents, 64bit, mmp and flex_bg or huge_file, gdt_csum, dir_nlink and extra_isize only
+    // exist from ext4 onwards, a journal from ext3.
+    let fs_type = if incompat & 0x3c0 != 0 || ro_compat & 0x78 != 0 {
+        FilesystemType::Ext4
+    } else if compat & 0x4 != 0 {
+        FilesystemType::Ext3
+    } else {
+        FilesystemType::Ext2
+    };
+    Ok(Some(Filesystem {
+        fs_type,
+        label: text(&sb[120..136]),
+        uuid: uuid(&sb[104..120]),
+    }))
+}
+
+fn swap<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    // The signature is at the end of the first page, which depends on the page size of the
+    // machine that created it.
+    for &page_size in &[4096, 8192, 16384, 65536] {
+        match read_at(dev, page_size - 10, 10)? {
+            Some(ref magic) if magic == b"SWAPSPACE2" => {}
+            _ => continue,
+        }
+        let header = match read_at(dev, 1024, 512)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        return Ok(Some(Filesystem {
+            fs_type: FilesystemType::Swap,
+            label: text(&header[28..44]),
+            uuid: uuid(&header[12..28]),
+        }));
+    }
+    Ok(None)
+}
+
+fn ntfs<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    let boot = match read_at(dev, 0, 512)? {
+        Some(ref boot) if &boot[3..11] == b"NTFS    " => boot.clone(),
+        _ => return Ok(None),
+    };
+    let uuid = Some(format!("{:016X}", le64(&boot[72..])));
+    let label = ntfs_label(dev, &boot)?;
+    Ok(Some(Filesystem {
+        fs_type: FilesystemType::Ntfs,
+        label,
+        uuid,
+    }))
+}
+
+/// Reads the volume name from the `$Volume` file, which is always record 3 of the MFT.
+fn ntfs_label<R: Read + Seek>(dev: &mut R, boot: &[u8]) -> io::Result<Option<String>> {
+    let cluster_size = u64::from(le16(&boot[11..])) * u64::from(boot[13]);
+    let record_size = match boot[64] as i8 {
+        n if n > 0 => u64::from(n as u8) * cluster_size,
+        n if n < 0 && n > -32 => 1 << -n,
+        _ => return Ok(None),
+    };
+    if cluster_size == 0 || !(512..=64 * 1024).contains(&record_size) {
+        return Ok(None);
+    }
+    // The MFT location comes straight from the boot sector so a corrupt one may not fit in a u64.
+    let offset = match le64(&boot[48..])
+        .checked_mul(cluster_size)
+        .and_then(|mft| mft.checked_add(3 * record_size))
+    {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+    let mut record = match read_at(dev, offset, record_size as usize)? {
+        Some(ref record) if record.starts_with(b"FILE") => record.clone(),
+        _ => return Ok(None),
+    };
+
+    // The last two bytes of each sector are swapped out for a sequence number that has to be
+    // put back before the record can be read.
+    let usa_offset = le16(&record[4..]) as usize;
+    let usa_count = le16(&record[6..]) as usize;
+    if usa_offset + usa_count * 2 > record.len() || (usa_count.max(1) - 1) * 512 > record.len() {
+        return Ok(None);
+    }
+    for i in 1..usa_count {
+        let (fixup, end) = (usa_offset + i * 2, i * 512);
+        record[end - 2] = record[fixup];
+        record[end - 1] = record[fixup + 1];
+    }
+
+    let mut attr = le16(&record[20..]) as usize;
+    while attr + 24 <= record.len() {
+        let attr_type = le32(&record[attr..]);
+        let len = le32(&record[attr + 4..]) as usize;
+        if attr_type == 0xffff_ffff || len == 0 {
+            break;
+        }
+        // The volume name is always resident.
+        if attr_type == 0x60 && record[attr + 8] == 0 {
+            let value_len = le32(&record[attr + 16..]) as usize;
+            let value = attr + le16(&record[attr + 20..]) as usize;
+            return Ok(record.get(value..value + value_len).and_then(utf16));
+        }
+        attr += len;
+    }
+    Ok(None)
+}
+
+fn exfat<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    let boot = match read_at(dev, 0, 512)? {
+        Some(ref boot) if &boot[3..11] == b"EXFAT   " => boot.clone(),
+        _ => return Ok(None),
+    };
+    let uuid = Some(serial(&boot[100..104]));
+    let label = exfat_label(dev, &boot)?;
+    Ok(Some(Filesystem {
+        fs_type: FilesystemType::Exfat,
+        label,
+        uuid,
+    }))
+}
+
+/// Reads the volume label entry from the first cluster of the root directory.
+fn exfat_label<R: Read + Seek>(dev: &mut R, boot: &[u8]) -> io::Result<Option<String>> {
+    let (sector_shift, cluster_shift) = (u64::from(boot[108]), u64::from(boot[109]));
+    let root_cluster = u64::from(le32(&boot[96..]));
+    if sector_shift < 9 || sector_shift + cluster_shift > 25 || root_cluster < 2 {
+        return Ok(None);
+    }
+    let offset = (u64::from(le32(&boot[88..])) << sector_shift)
+        + ((root_cluster - 2) << (sector_shift + cluster_shift));
+    let dir = match read_at(dev, offset, 1 << (sector_shift + cluster_shift))? {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    for entry in dir.chunks(32) {
+        match entry[0] {
+            0x00 => break,
+            0x83 => {
+                let len = (entry[1] as usize).min(15) * 2;
+                return Ok(utf16(&entry[2..2 + len]));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+fn vfat<R: Read + Seek>(dev: &mut R) -> io::Result<Option<Filesystem>> {
+    let boot = match read_at(dev, 0, 512)? {
+        Some(ref boot) if boot[510..512] == [0x55, 0xaa] => boot.clone(),
+        _ => return Ok(None),
+    };
+    // FAT32 moves the extended boot record further into the boot sector.
+    let ebr = if &boot[82..87] == b"FAT32" {
+        64
+    } else if &boot[54..57] == b"FAT" {
+        36
+    } else {
+        return Ok(None);
+    };
+    let label = text(&boot[ebr + 7..ebr + 18]).filter(|label| label != "NO NAME");
+    Ok(Some(Filesystem {
+        fs_type: FilesystemType::Vfat,
+        label,
+        uuid: Some(serial(&boot[ebr + 3..ebr + 7])),
+    }))
+}
+
+/// Reads `len` bytes from `offset`, returning None if the device is not that big.
+fn read_at<R: Read + Seek>(dev: &mut R, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+    dev.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len];
+    match dev.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+    u64::from(le32(bytes)) | u64::from(le32(&bytes[4..])) << 32
+}
+
+/// A fixed size text field padded with spaces or nulls.
+fn text(bytes: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.trim_end_matches(['\0', ' ']).trim_start();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn utf16(bytes: &[u8]) -> Option<String> {
+    let units = bytes.chunks(2).map(le16).collect::<Vec<_>>();
+    Some(String::from_utf16_lossy(&units)).filter(|text| !text.is_empty())
+}
+
+/// A 16 byte uuid in its usual hyphenated form.
+fn uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// A 4 byte FAT style volume serial number, shown as `ABCD-1234`.
+fn serial(bytes: &[u8]) -> String {
+    let serial = le32(bytes);
+    format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)
+}
+
+impl fmt::Display for FilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            FilesystemType::Vfat => "vfat",
+            FilesystemType::Exfat => "exfat",
+            FilesystemType::Ntfs => "ntfs",
+            FilesystemType::Ext2 => "ext2",
+            FilesystemType::Ext3 => "ext3",
+            FilesystemType::Ext4 => "ext4",
+            FilesystemType::Iso9660 => "iso9660",
+            FilesystemType::Swap => "swap",
+            FilesystemType::Luks => "luks",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn probe_bytes(image: Vec<u8>) -> Option<Filesystem> {
+        probe(&mut Cursor::new(image)).unwrap()
+    }
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn utf16_bytes(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn probes_fat_and_ext() {
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 67, &[0x78, 0x56, 0x34, 0x12]);
+        put(&mut image, 71, b"BOOT       FAT32   ");
+        put(&mut image, 510, &[0x55, 0xaa]);
+        assert_eq!(
+            probe_bytes(image),
+            Some(Filesystem {
+                fs_type: FilesystemType::Vfat,
+                label: Some("BOOT".to_string()),
+                uuid: Some("1234-5678".to_string()),
+            })
+        );
+
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 1024 + 56, &[0x53, 0xef]);
+        put(&mut image, 1024 + 96, &[0x40, 0x02, 0, 0]);
+        put(&mut image, 1024 + 104, &[0x12; 16]);
+        put(&mut image, 1024 + 120, b"rootfs");
+        assert_eq!(
+            probe_bytes(image),
+            Some(Filesystem {
+                fs_type: FilesystemType::Ext4,
+                label: Some("rootfs".to_string()),
+                uuid: Some("12121212-1212-1212-1212-121212121212".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn probes_swap_luks_and_iso9660() {
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 1024 + 12, &[0xab; 16]);
+        put(&mut image, 4096 - 10, b"SWAPSPACE2");
+        let swap = probe_bytes(image).unwrap();
+        assert_eq!(swap.fs_type, FilesystemType::Swap);
+        assert_eq!(swap.label, None);
+        assert_eq!(
+            swap.uuid,
+            Some("abababab-abab-abab-abab-abababababab".to_string())
+        );
+
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 0, b"LUKS\xba\xbe\x00\x02");
+        put(&mut image, 24, b"secrets");
+        put(&mut image, 168, b"0b7a6c3e-1d2f-4a5b-8c9d-0e1f2a3b4c5d");
+        let luks = probe_bytes(image).unwrap();
+        assert_eq!(luks.fs_type, FilesystemType::Luks);
+        assert_eq!(luks.label, Some("secrets".to_string()));
+        assert_eq!(
+            luks.uuid,
+            Some("0b7a6c3e-1d2f-4a5b-8c9d-0e1f2a3b4c5d".to_string())
+        );
+
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 0x8001, b"CD001");
+        put(&mut image, 0x8000 + 40, b"Ubuntu 18.04 LTS amd64          ");
+        put(&mut image, 0x8000 + 813, b"2018042617260500");
+        assert_eq!(
+            probe_bytes(image),
+            Some(Filesystem {
+                fs_type: FilesystemType::Iso9660,
+                label: Some("Ubuntu 18.04 LTS amd64".to_string()),
+                uuid: Some("2018-04-26-17-26-05-00".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn probes_ntfs_and_exfat_labels() {
+        // A 512 byte cluster NTFS volume with 1KiB MFT records starting at cluster 4.
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 3, b"NTFS    ");
+        put(&mut image, 11, &[0x00, 0x02, 1]);
+        put(&mut image, 48, &[4, 0, 0, 0, 0, 0, 0, 0]);
+        put(&mut image, 64, &[0xf6]);
+        put(&mut image, 72, &[0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01]);
+        put(&mut image, 510, &[0x55, 0xaa]);
+        let record = 4 * 512 + 3 * 1024;
+        put(&mut image, record, b"FILE");
+        put(&mut image, record + 4, &[48, 0, 3, 0]);
+        put(&mut image, record + 20, &[56, 0]);
+        // The update sequence number and the real last two bytes of each sector.
+        put(&mut image, record + 48, &[1, 0, 0, 0, 0, 0]);
+        put(&mut image, record + 510, &[1, 0]);
+        put(&mut image, record + 1022, &[1, 0]);
+        let name = utf16_bytes("Windows");
+        put(&mut image, record + 56, &[0x60, 0, 0, 0, 40, 0, 0, 0, 0]);
+        put(&mut image, record + 56 + 16, &[name.len() as u8, 0, 0, 0, 24, 0]);
+        put(&mut image, record + 56 + 24, &name);
+        put(&mut image, record + 96, &[0xff; 4]);
+        assert_eq!(
+            probe_bytes(image),
+            Some(Filesystem {
+                fs_type: FilesystemType::Ntfs,
+                label: Some("Windows".to_string()),
+                uuid: Some("0123456789ABCDEF".to_string()),
+            })
+        );
+
+        // An MFT location past the end of any disk only loses the label.
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 3, b"NTFS    ");
+        put(&mut image, 11, &[0x00, 0x02, 128]);
+        put(&mut image, 48, &[0xff; 8]);
+        put(&mut image, 64, &[0xf6]);
+        put(&mut image, 510, &[0x55, 0xaa]);
+        assert_eq!(
+            probe_bytes(image),
+            Some(Filesystem {
+                fs_type: FilesystemType::Ntfs,
+                label: None,
+                uuid: Some("0000000000000000".to_string()),
+            })
+        );
+
+        // A 512 byte sector, 4KiB cluster exFAT volume with the cluster heap at sector 32.
+        let mut image = vec![0; 64 * 1024];
+        put(&mut image, 3, b"EXFAT   ");
+        put(&mut image, 88, &[32, 0, 0, 0]);
+        put(&mut image, 96, &[4, 0, 0, 0, 0x78, 0x56, 0x34, 0x12]);
+        put(&mut image, 108, &[9, 3]);
+        put(&mut image, 510, &[0x55, 0xaa]);
+        let root = 32 * 512 + 2 * 4096;
+        put(&mut image, root, &[0x81]);
+        put(&mut image, root + 32, &[0x83, 4]);
+        put(&mut image, root + 34, &utf16_bytes("DATA"));
+        assert_eq!(
+            probe_bytes(image),
+            Some(Filesystem {
+                fs_type: FilesystemType::Exfat,
+                label: Some("DATA".to_string()),
+                uuid: Some("1234-5678".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_and_short_devices() {
+        assert_eq!(probe_bytes(vec![0; 64 * 1024]), None);
+        assert_eq!(probe_bytes(vec![0; 100]), None);
+    }
+}
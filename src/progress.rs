@@ -0,0 +1,295 @@
+use block_dev::Size;
+use std::collections::VecDeque;
+use std::io::{self, stdout, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use termion;
+
+/// How far back samples are kept when working out the current transfer rate.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// The minimum time between redraws of the progress line.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A single line progress bar that redraws itself in place showing how many bytes have been
+/// processed, the transfer rate averaged over the last few seconds and the estimated time
+/// remaining.
+///
+/// When the source is compressed the number of compressed bytes read is tracked alongside the
+/// bytes written, and is used for the bar and ETA as the size of the decompressed data is not
+/// always known up front.
+///
+/// Progress made by `MultiProgress::add` does not draw itself, it is drawn along with the others
+/// by the `MultiProgress` instead, and can be sent to another thread to be updated from there.
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+    /// Whether the progress line is drawn when it is updated.
+    standalone: bool,
+}
+
+/// Draws several progress lines, one under the other, such as one for each device being written
+/// to at the same time.
+pub struct MultiProgress {
+    lines: Vec<Arc<Mutex<State>>>,
+    /// Whether the lines have been drawn before and so need to be drawn over.
+    drawn: bool,
+    /// When the lines were last drawn to the terminal.
+    last_draw: Option<Instant>,
+}
+
+struct State {
+    /// The text shown before the bar, ie "Writing" or "Flushing".
+    label: String,
+    /// The total number of bytes expected to be processed, if known.
+    total: Option<u64>,
+    /// The number of bytes processed so far.
+    done: u64,
+    /// The number of compressed bytes read so far and the total number there are to read.
+    read: Option<(u64, u64)>,
+    /// Recent (time, bytes done, bytes read) samples used to calculate a moving average rate.
+    samples: VecDeque<(Instant, u64, u64)>,
+    /// When the progress was last drawn to the terminal.
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(label: impl Into<String>, total: impl Into<Option<u64>>) -> Progress {
+        Progress {
+            state: Arc::new(Mutex::new(State::new(label.into(), total.into()))),
+            standalone: true,
+        }
+    }
+
+    /// Starts a new phase with a different label and total, resetting the rate calculation.
+    pub fn relabel(&mut self, label: impl Into<String>, total: impl Into<Option<u64>>) {
+        self.state().relabel(label.into(), total.into());
+    }
+
+    /// The total number of bytes expected to be processed, if known.
+    pub fn total(&self) -> Option<u64> {
+        self.state().total
+    }
+
+    /// Records how many of the `total` compressed bytes have been read so far.
+    pub fn set_read(&mut self, read: u64, total: u64) {
+        self.state().read = Some((read, total));
+    }
+
+    /// Adds `bytes` to the number of bytes done and redraws the progress line if needed.
+    pub fn inc(&mut self, bytes: u64) {
+        let done = self.state().done + bytes;
+        self.set(done);
+    }
+
+    /// Sets the number of bytes done and redraws the progress line if needed.
+    pub fn set(&mut self, done: u64) {
+        let mut state = self.state();
+        state.set(done);
+        if self.standalone && state.should_draw() {
+            // A failure to draw the progress should never stop the write itself.
+            let _ = state.draw(&mut stdout());
+        }
+    }
+
+    /// Draws the final state of the progress line and moves onto the next line.
+    pub fn finish(&mut self) {
+        if self.standalone {
+            let mut stdout = stdout();
+            let _ = self.state().draw(&mut stdout).and_then(|_| writeln!(stdout));
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // The state is only ever locked to update a few numbers, which cannot panic part way.
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl MultiProgress {
+    pub fn new() -> MultiProgress {
+        MultiProgress {
+            lines: Vec::new(),
+            drawn: false,
+            last_draw: None,
+        }
+    }
+
+    /// Adds a line, returning the progress that updates it.
+    pub fn add(&mut self, label: impl Into<String>, total: impl Into<Option<u64>>) -> Progress {
+        let state = Arc::new(Mutex::new(State::new(label.into(), total.into())));
+        self.lines.push(state.clone());
+        Progress {
+            state,
+            standalone: false,
+        }
+    }
+
+    /// Redraws all of the lines in place, unless they were drawn very recently.
+    pub fn draw(&mut self) {
+        let now = Instant::now();
+        if self.last_draw.is_none_or(|last| now - last >= REDRAW_INTERVAL) {
+            self.last_draw = Some(now);
+            let _ = self.draw_lines(&mut stdout());
+        }
+    }
+
+    /// Draws the final state of the lines and moves onto the next line.
+    pub fn finish(&mut self) {
+        let mut stdout = stdout();
+        let _ = self.draw_lines(&mut stdout).and_then(|_| writeln!(stdout));
+    }
+
+    fn draw_lines(&mut self, w: &mut impl Write) -> io::Result<()> {
+        if self.drawn && self.lines.len() > 1 {
+            write!(w, "{}", termion::cursor::Up(self.lines.len() as u16 - 1))?;
+        }
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(w)?;
+            }
+            line.lock().unwrap_or_else(|err| err.into_inner()).draw(w)?;
+        }
+        self.drawn = true;
+        w.flush()
+    }
+}
+
+impl State {
+    fn new(label: String, total: Option<u64>) -> State {
+        State {
+            label,
+            total,
+            done: 0,
+            read: None,
+            samples: VecDeque::new(),
+            last_draw: None,
+        }
+    }
+
+    fn relabel(&mut self, label: String, total: Option<u64>) {
+        self.label = label;
+        self.total = total;
+        self.done = 0;
+        self.read = None;
+        self.samples.clear();
+        self.last_draw = None;
+    }
+
+    fn set(&mut self, done: u64) {
+        let now = Instant::now();
+        self.done = done;
+        let read = self.read.map(|(read, _)| read).unwrap_or(0);
+        self.samples.push_back((now, done, read));
+        while self.samples.len() > 2 && now - self.samples[0].0 > RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns true if it is time to redraw, recording that it is being drawn now.
+    fn should_draw(&mut self) -> bool {
+        let now = Instant::now();
+        if self.last_draw.is_none_or(|last| now - last >= REDRAW_INTERVAL) {
+            self.last_draw = Some(now);
+            return true;
+        }
+        false
+    }
+
+    /// The number of bytes processed per second averaged over the last few seconds.
+    fn rate(&self) -> Option<f64> {
+        self.rates().map(|(done, _)| done)
+    }
+
+    /// The estimated time left based on the current rate. When compressed bytes are being
+    /// tracked the estimate is based on those instead.
+    fn eta(&self) -> Option<Duration> {
+        let (done_rate, read_rate) = self.rates()?;
+        let (remaining, rate) = match (self.read, self.total) {
+            (Some((read, total)), _) => (total.saturating_sub(read), read_rate),
+            (None, Some(total)) => (total.saturating_sub(self.done), done_rate),
+            (None, None) => return None,
+        };
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs((remaining as f64 / rate).ceil() as u64))
+    }
+
+    /// The number of bytes done and bytes read per second.
+    fn rates(&self) -> Option<(f64, f64)> {
+        let (first_time, first_done, first_read) = *self.samples.front()?;
+        let (last_time, last_done, last_read) = *self.samples.back()?;
+        let elapsed = last_time - first_time;
+        if elapsed < Duration::from_millis(500) {
+            return None;
+        }
+        let elapsed = elapsed.as_secs_f64();
+        Some((
+            (last_done - first_done) as f64 / elapsed,
+            (last_read - first_read) as f64 / elapsed,
+        ))
+    }
+
+    /// How far through the work we are, between 0 and 1.
+    fn fraction(&self) -> Option<f64> {
+        match (self.read, self.total) {
+            (Some((_, 0)), _) | (None, Some(0)) => Some(1.0),
+            (Some((read, total)), _) => Some(read.min(total) as f64 / total as f64),
+            (None, Some(total)) => Some(self.done.min(total) as f64 / total as f64),
+            (None, None) => None,
+        }
+    }
+
+    fn draw(&self, w: &mut impl Write) -> io::Result<()> {
+        let rate = match self.rate() {
+            Some(rate) => format!("{:.1}MB/s", rate / 1_000_000.0),
+            None => "--.-MB/s".to_string(),
+        };
+        let eta = match self.eta() {
+            Some(eta) => format_duration(eta),
+            None => "--:--".to_string(),
+        };
+        let done = match self.total {
+            Some(total) => format!("{}/{}", Size::from_bytes(self.done), Size::from_bytes(total)),
+            None => format!("{}", Size::from_bytes(self.done)),
+        };
+        let read = match self.read {
+            Some((read, total)) => format!(
+                " ({}/{} read)",
+                Size::from_bytes(read),
+                Size::from_bytes(total)
+            ),
+            None => String::new(),
+        };
+        let stats = format!(" {}{} {} ETA {}", done, read, rate, eta);
+
+        let width = termion::terminal_size()
+            .map(|(w, _)| w as usize)
+            .unwrap_or(80);
+        let bar_width = width
+            .saturating_sub(self.label.len() + stats.len() + 4)
+            .min(50);
+        let filled = self.fraction()
+            .map(|fraction| (bar_width as f64 * fraction) as usize)
+            .unwrap_or(0);
+
+        write!(
+            w,
+            "\r{}{} [{}{}]{}",
+            termion::clear::CurrentLine,
+            self.label,
+            "#".repeat(filled),
+            " ".repeat(bar_width - filled),
+            stats,
+        )?;
+        w.flush()
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,242 @@
+use block_dev::{BlockDevice, DeviceType, Flags};
+use csv;
+use failure::Error;
+use serde_json;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The version of the machine readable output. This must be bumped whenever a field is removed,
+/// renamed or changes meaning so that scripts can detect the change. Adding fields does not
+/// require a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The output formats supported by `scribe list`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+    Tsv,
+}
+
+/// A block device as it appears in the machine readable output.
+#[derive(Debug, Serialize)]
+pub struct DeviceRecord {
+    pub dev_file: String,
+    pub sys_path: String,
+    pub label: String,
+    pub size_bytes: u64,
+    pub device_type: &'static str,
+    /// The bus the device is attached through, such as usb or nvme, if known.
+    pub transport: Option<String>,
+    pub flags: Vec<&'static str>,
+    /// Whether the device would be listed by `scribe list` with the same options.
+    pub included: bool,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    version: u32,
+    devices: &'a [DeviceRecord],
+}
+
+/// A flat row for csv and tsv output, where the flags are joined by spaces.
+#[derive(Serialize)]
+struct Row<'a> {
+    version: u32,
+    dev_file: &'a str,
+    sys_path: &'a str,
+    label: &'a str,
+    size_bytes: u64,
+    device_type: &'a str,
+    transport: Option<&'a str>,
+    flags: String,
+    included: bool,
+}
+
+impl DeviceRecord {
+    pub fn new(blkdev: &BlockDevice, included: bool) -> DeviceRecord {
+        DeviceRecord {
+            dev_file: blkdev.dev_file().to_string_lossy().into_owned(),
+            sys_path: blkdev.sys_path().to_string_lossy().into_owned(),
+            label: blkdev.label().to_string(),
+            size_bytes: blkdev.size().bytes(),
+            device_type: device_type_id(blkdev.device_type()),
+            transport: blkdev.transport().map(|transport| transport.to_string()),
+            flags: blkdev.flags().iter().map(flag_id).collect(),
+            included,
+        }
+    }
+}
+
+/// Writes the devices in the given format. `Format::Text` is handled by the caller.
+pub fn write(w: &mut impl Write, format: Format, devices: &[DeviceRecord]) -> Result<(), Error> {
+    match format {
+        Format::Text => {}
+        Format::Json => {
+            serde_json::to_writer_pretty(
+                &mut *w,
+                &JsonReport {
+                    version: SCHEMA_VERSION,
+                    devices,
+                },
+            )?;
+            writeln!(w)?;
+        }
+        Format::Csv | Format::Tsv => {
+            let mut writer = csv::WriterBuilder::new()
+                .delimiter(if format == Format::Csv { b',' } else { b'\t' })
+                .from_writer(w);
+            for device in devices {
+                writer.serialize(Row {
+                    version: SCHEMA_VERSION,
+                    dev_file: &device.dev_file,
+                    sys_path: &device.sys_path,
+                    label: &device.label,
+                    size_bytes: device.size_bytes,
+                    device_type: device.device_type,
+                    transport: device.transport.as_deref(),
+                    flags: device.flags.join(" "),
+                    included: device.included,
+                })?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// The stable identifier used for a device type in machine readable output.
+fn device_type_id(device_type: DeviceType) -> &'static str {
+    match device_type {
+        DeviceType::FlashDrive => "flash_drive",
+        DeviceType::SDMMC => "sdmmc",
+        DeviceType::InternalDrive => "internal_drive",
+        DeviceType::ExternalDrive => "external_drive",
+        DeviceType::CDROM => "cdrom",
+        DeviceType::LoopBack => "loopback",
+        DeviceType::VirtualDrive => "virtual_drive",
+        DeviceType::Raid => "raid",
+        DeviceType::DeviceMapper => "device_mapper",
+        DeviceType::RamDisk => "ram_disk",
+        DeviceType::NetworkBlock => "network_block",
+    }
+}
+
+/// The stable identifier used for a flag in machine readable output.
+fn flag_id(flag: &Flags) -> &'static str {
+    match flag {
+        Flags::Mounted => "mounted",
+        Flags::ZeroSize => "zero_size",
+        Flags::ReadOnly => "read_only",
+        Flags::Large => "large",
+        Flags::TooSmall => "too_small",
+        Flags::InUse => "in_use",
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(format!("unknown format '{}'", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> Vec<DeviceRecord> {
+        vec![
+            DeviceRecord {
+                dev_file: "/dev/sdb".to_string(),
+                sys_path: "/sys/block/sdb".to_string(),
+                label: "SanDisk, Ultra".to_string(),
+                size_bytes: 32_017_047_552,
+                device_type: "flash_drive",
+                transport: Some("usb".to_string()),
+                flags: vec!["mounted", "in_use"],
+                included: true,
+            },
+            DeviceRecord {
+                dev_file: "/dev/nvme0n1".to_string(),
+                sys_path: "/sys/block/nvme0n1".to_string(),
+                label: "Samsung SSD 970".to_string(),
+                size_bytes: 500_107_862_016,
+                device_type: "internal_drive",
+                transport: None,
+                flags: vec![],
+                included: false,
+            },
+        ]
+    }
+
+    fn render(format: Format) -> String {
+        let mut out = Vec::new();
+        write(&mut out, format, &devices()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_json() {
+        assert_eq!(
+            render(Format::Json),
+            r#"{
+  "version": 1,
+  "devices": [
+    {
+      "dev_file": "/dev/sdb",
+      "sys_path": "/sys/block/sdb",
+      "label": "SanDisk, Ultra",
+      "size_bytes": 32017047552,
+      "device_type": "flash_drive",
+      "transport": "usb",
+      "flags": [
+        "mounted",
+        "in_use"
+      ],
+      "included": true
+    },
+    {
+      "dev_file": "/dev/nvme0n1",
+      "sys_path": "/sys/block/nvme0n1",
+      "label": "Samsung SSD 970",
+      "size_bytes": 500107862016,
+      "device_type": "internal_drive",
+      "transport": null,
+      "flags": [],
+      "included": false
+    }
+  ]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn writes_csv() {
+        assert_eq!(
+            render(Format::Csv),
+            "version,dev_file,sys_path,label,size_bytes,device_type,transport,flags,included\n\
+             1,/dev/sdb,/sys/block/sdb,\"SanDisk, Ultra\",32017047552,flash_drive,usb,mounted in_use,true\n\
+             1,/dev/nvme0n1,/sys/block/nvme0n1,Samsung SSD 970,500107862016,internal_drive,,,false\n"
+        );
+    }
+
+    #[test]
+    fn writes_tsv() {
+        assert_eq!(
+            render(Format::Tsv),
+            "version\tdev_file\tsys_path\tlabel\tsize_bytes\tdevice_type\ttransport\tflags\tincluded\n\
+             1\t/dev/sdb\t/sys/block/sdb\tSanDisk, Ultra\t32017047552\tflash_drive\tusb\tmounted in_use\ttrue\n\
+             1\t/dev/nvme0n1\t/sys/block/nvme0n1\tSamsung SSD 970\t500107862016\tinternal_drive\t\t\tfalse\n"
+        );
+    }
+}
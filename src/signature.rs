@@ -0,0 +1,103 @@
+use failure::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Extensions of the detached signatures looked for next to a signed file, such as
+/// `disk.img.xz.sig` or `SHA256SUMS.gpg`.
+const SIGNATURE_EXTENSIONS: &[&str] = &["sig", "asc", "gpg", "sign"];
+
+/// Finds the detached signature of `signed` next to it.
+pub fn find(signed: &Path) -> Option<PathBuf> {
+    let name = signed.file_name()?;
+    SIGNATURE_EXTENSIONS
+        .iter()
+        .map(|ext| {
+            let mut name = OsString::from(name);
+            name.push(".");
+            name.push(ext);
+            signed.with_file_name(name)
+        })
+        .find(|path| path.is_file())
+}
+
+/// Checks that `signature` is a good detached signature of `signed` from one of the keys in the
+/// keyrings in `keyring_dir`, using `gpgv` so that no other keys the user may have are trusted.
+/// Returns what gpgv said about the signature.
+pub fn verify(signed: &Path, signature: &Path, keyring_dir: &Path) -> Result<String, Error> {
+    let keyrings = keyrings(keyring_dir).map_err(|err| {
+        format_err!(
+            "could not read the keyring directory '{}': {}",
+            keyring_dir.display(),
+            err
+        )
+    })?;
+    if keyrings.is_empty() {
+        bail!("there are no keyrings in '{}'", keyring_dir.display());
+    }
+
+    let mut gpgv = Command::new("gpgv");
+    // Pointing the home directory at the keyrings keeps gpgv from using the default trusted keys.
+    gpgv.arg("--homedir").arg(keyring_dir);
+    for keyring in &keyrings {
+        gpgv.arg("--keyring").arg(keyring);
+    }
+    let output = gpgv
+        .arg(signature)
+        .arg(signed)
+        .output()
+        .map_err(|err| format_err!("could not run gpgv, which is needed to check signatures: {}", err))?;
+
+    let message = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(|line| line.trim_start_matches("gpgv: "))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !output.status.success() {
+        bail!("{}", message);
+    }
+    Ok(message)
+}
+
+/// Lists the keyring files in `dir`, in a stable order.
+fn keyrings(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = dir.canonicalize()?;
+    let mut keyrings = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && entry.file_type()?.is_file() {
+            keyrings.push(entry.path());
+        }
+    }
+    keyrings.sort();
+    Ok(keyrings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use util::test_dir;
+
+    #[test]
+    fn finds_signatures_next_to_files() {
+        let dir = test_dir("signatures");
+        let image = dir.join("disk.img.xz");
+        assert_eq!(find(&image), None);
+
+        File::create(dir.join("disk.img.xz.gpg")).unwrap();
+        assert_eq!(find(&image), Some(dir.join("disk.img.xz.gpg")));
+        File::create(dir.join("disk.img.xz.sig")).unwrap();
+        assert_eq!(find(&image), Some(dir.join("disk.img.xz.sig")));
+
+        File::create(dir.join(".hidden")).unwrap();
+        assert_eq!(
+            keyrings(&dir).unwrap(),
+            vec![dir.join("disk.img.xz.gpg"), dir.join("disk.img.xz.sig")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
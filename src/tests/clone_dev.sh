@@ -5,7 +5,8 @@ IFS=$'\n\t'
 
 src="${1?"Missing source device"}"
 dev_name="${2?"Missing dest device name"}"
-dest="$(dirname $(readlink -f $0))/sysfs/${dev_name}"
+tests="$(dirname $(readlink -f $0))"
+dest="${tests}/sysfs/${dev_name}"
 
 [[ ! -e "$dest" ]] || { echo "$dest already exists, not overwriting"; exit 1; }
 echo "$src -> $dest"
@@ -19,11 +20,32 @@ cp_if_exists() {
 mkdir "${dest}"
 cp_if_exists removable size ro hidden dev
 
+if [[ -d "${src}/queue/" ]] ; then
+    mkdir "${dest}/queue"
+    cp_if_exists queue/rotational
+fi
+
 if [[ -d "${src}/device/" ]] ; then
-    mkdir "${dest}/device"
+    # The transport is worked out from the path the device symlink points to, so recreate it
+    # under devices/ and link to it like sysfs does.
+    device="$(readlink -f "${src}/device")"
+    device_dest="${tests}/devices/${device#/sys/devices/}"
+    mkdir -p "${device_dest}"
+    ln -s "$(realpath --relative-to="${dest}" "${device_dest}")" "${dest}/device"
     cp_if_exists device/model device/vendor
     if [[ -d "${src}/device/device/" ]] ; then
         mkdir "${dest}/device/device"
         cp_if_exists device/device/model device/device/vendor
     fi
+
+    # The serial number is read from the nearest parent that has one, USB drives keep it on the
+    # USB device rather than the SCSI one.
+    parent="${device}"
+    while [[ "${parent}" == /sys/devices/* ]] ; do
+        if [[ -e "${parent}/serial" ]] ; then
+            cp "${parent}/serial" "${tests}/devices/${parent#/sys/devices/}/serial"
+            break
+        fi
+        parent="$(dirname "${parent}")"
+    done
 fi
@@ -9,3 +9,15 @@ macro_rules! if_exists {
         }
     };
 }
+
+/// Creates an empty directory for the files of the test `name`, removing anything left there by an
+/// earlier run. The process id is part of the path so that test runs at the same time never share
+/// a directory.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> ::std::path::PathBuf {
+    use std::{env, fs, process};
+    let dir = env::temp_dir().join(format!("scribe-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,74 @@
+use std::process::{Command, Output};
+
+/// Runs `scribe list` against the device snapshots in `src/tests`.
+fn list(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_scribe"))
+        .args([
+            "--sysfs-root",
+            "src/tests/sysfs",
+            "--mounts-file",
+            "src/tests/mountinfo",
+            "--swaps-file",
+            "src/tests/swaps",
+            "list",
+        ])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+/// The device files at the start of each line, sorted as the devices are listed in whatever order
+/// the directory gives them.
+fn dev_files(output: &Output) -> Vec<String> {
+    let mut dev_files = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with("/dev/"))
+        .map(|line| line.split_whitespace().next().unwrap().to_string())
+        .collect::<Vec<_>>();
+    dev_files.sort();
+    dev_files
+}
+
+#[test]
+fn lists_safe_devices_from_a_snapshot() {
+    assert_eq!(
+        dev_files(&list(&[])),
+        ["/dev/mmcblk0", "/dev/sdb", "/dev/sdc", "/dev/sde", "/dev/sdk"]
+    );
+}
+
+#[test]
+fn explains_unsafe_devices_from_a_snapshot() {
+    let output = list(&["--reasons"]);
+    assert_eq!(dev_files(&output).len(), 25);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for reason in &[
+        "it is currently mounted: /dev/sda2 on /mnt, /dev/sda1 on /media/data",
+        "it is in use by the system: sdj1 is used by dm-0 (vg0-root), /dev/sdj2 is active swap",
+        "it looks like an external hard drive",
+    ] {
+        assert!(stdout.contains(reason), "{}", stdout);
+    }
+}
+
+#[test]
+fn marks_the_devices_the_table_would_list() {
+    let included = |args: &[&str]| {
+        let output = list(args);
+        let mut included = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .skip(1)
+            .filter(|line| line.ends_with(",true"))
+            .map(|line| line.split(',').nth(1).unwrap().to_string())
+            .collect::<Vec<_>>();
+        included.sort();
+        included
+    };
+    assert_eq!(included(&["--format", "csv"]), dev_files(&list(&[])));
+    assert_eq!(
+        included(&["--format", "csv", "--reasons"]),
+        dev_files(&list(&["--reasons"]))
+    );
+}